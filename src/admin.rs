@@ -0,0 +1,181 @@
+use anyhow::Context;
+use http::{Method, Response, StatusCode};
+use pingora::apps::http_app::ServeHttp;
+use pingora::protocols::http::ServerSession;
+use serde::Serialize;
+use std::net::SocketAddr;
+
+use crate::config::ConfigStatus;
+use crate::config::provider::ConfigProvider;
+use crate::proxy::Gateway;
+use crate::tls::TlsResolver;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:7766";
+
+/// Introspection of routes, the last config refresh and certificates.
+///
+/// Reads are open to anyone who can reach the port, which is why it binds to
+/// localhost by default. Writes need `Authorization: Bearer <ADMIN_TOKEN>`
+/// and are disabled without a token.
+pub struct AdminService<P> {
+    gateway: Gateway,
+    config: ConfigStatus,
+    tls: Option<TlsResolver<P>>,
+    token: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Message<'a> {
+    message: &'a str,
+}
+
+#[derive(Serialize)]
+struct Error<'a> {
+    error: &'a str,
+}
+
+impl<P: ConfigProvider + Send + Sync + 'static> AdminService<P> {
+    pub fn new(
+        gateway: Gateway,
+        config: ConfigStatus,
+        tls: Option<TlsResolver<P>>,
+    ) -> anyhow::Result<Self> {
+        let token = match std::env::var("ADMIN_TOKEN") {
+            Ok(token) => Some(token),
+            Err(_) => match std::env::var("ADMIN_TOKEN_FILE") {
+                Ok(path) => Some(
+                    std::fs::read_to_string(&path)
+                        .with_context(|| format!("failed to read ADMIN_TOKEN_FILE {path}"))?,
+                ),
+                Err(_) => None,
+            },
+        };
+
+        let token = token.map(|t| t.trim().to_owned()).filter(|t| !t.is_empty());
+
+        Ok(Self {
+            gateway,
+            config,
+            tls,
+            token,
+        })
+    }
+
+    /// `ADMIN_ADDR`, localhost only unless configured otherwise.
+    pub fn addr() -> String {
+        std::env::var("ADMIN_ADDR").unwrap_or_else(|_| DEFAULT_ADDR.to_owned())
+    }
+
+    /// The error response if the request doesn't carry the admin token.
+    fn reject_unauthorized(&self, session: &ServerSession) -> Option<Response<Vec<u8>>> {
+        let Some(token) = &self.token else {
+            return Some(error(
+                StatusCode::FORBIDDEN,
+                "write endpoints are disabled, set ADMIN_TOKEN",
+            ));
+        };
+
+        let presented = session
+            .req_header()
+            .headers
+            .get("authorization")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .unwrap_or_default();
+
+        let valid = presented.len() == token.len()
+            && openssl::memcmp::eq(presented.as_bytes(), token.as_bytes());
+
+        if !valid {
+            return Some(error(StatusCode::UNAUTHORIZED, "invalid admin token"));
+        }
+
+        None
+    }
+
+    async fn renew(&self, domain: &str) -> Response<Vec<u8>> {
+        let Some(tls) = &self.tls else {
+            return error(StatusCode::NOT_FOUND, "tls is disabled");
+        };
+
+        if !tls.is_routed(domain).await {
+            return error(StatusCode::NOT_FOUND, "domain is not routed");
+        }
+
+        // issuance takes longer than a client wants to wait, the result is logged
+        let tls = tls.clone();
+        let domain = domain.to_owned();
+        tokio::spawn(async move {
+            tracing::info!("forced renewal of cert for {domain} requested");
+            if let Err(err) = tls.renew(&domain).await {
+                tracing::error!("forced renewal of cert for {domain} failed: {err:?}");
+            }
+        });
+
+        json(
+            StatusCode::ACCEPTED,
+            &Message {
+                message: "renewal started",
+            },
+        )
+    }
+
+    async fn drain(&self, addr: &str, drained: bool) -> Response<Vec<u8>> {
+        let Ok(addr) = addr.parse::<SocketAddr>() else {
+            return error(StatusCode::BAD_REQUEST, "expected a backend as <ip>:<port>");
+        };
+
+        if !self.gateway.set_drained(addr, drained).await {
+            return error(StatusCode::NOT_FOUND, "no route uses this backend");
+        }
+
+        let action = if drained { "drained" } else { "restored" };
+        tracing::info!("backend {addr} {action} through admin api");
+
+        let message = format!("backend {action}");
+        json(StatusCode::OK, &Message { message: &message })
+    }
+}
+
+#[async_trait::async_trait]
+impl<P: ConfigProvider + Send + Sync + 'static> ServeHttp for AdminService<P> {
+    async fn response(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = session.req_header().method.clone();
+        let path = session.req_header().uri.path().to_owned();
+        let segments: Vec<_> = path.trim_matches('/').split('/').collect();
+
+        if method != Method::GET
+            && let Some(response) = self.reject_unauthorized(session)
+        {
+            return response;
+        }
+
+        match (&method, segments.as_slice()) {
+            (&Method::GET, ["routes"]) => json(StatusCode::OK, &self.gateway.routes().await),
+            (&Method::GET, ["config"]) => json(StatusCode::OK, &self.config.get()),
+            (&Method::GET, ["certificates"]) => match &self.tls {
+                Some(tls) => json(StatusCode::OK, &tls.certificates().await),
+                None => json(StatusCode::OK, &Vec::<()>::new()),
+            },
+            (&Method::POST, ["certificates", domain, "renew"]) => self.renew(domain).await,
+            (&Method::POST, ["backends", addr, "drain"]) => self.drain(addr, true).await,
+            (&Method::DELETE, ["backends", addr, "drain"]) => self.drain(addr, false).await,
+            _ => error(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+}
+
+fn json<T: Serialize>(status: StatusCode, body: &T) -> Response<Vec<u8>> {
+    let body = serde_json::to_vec_pretty(body).expect("admin responses must serialize");
+
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .header("content-length", body.len())
+        .body(body)
+        .expect("response must be valid")
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Vec<u8>> {
+    json(status, &Error { error: message })
+}
@@ -0,0 +1,145 @@
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use tracing::Level;
+use tracing_subscriber::FmtSubscriber;
+
+use crate::config::provider::{self, ConfigProvider};
+use crate::events::EventBus;
+use crate::redis::RedisClient;
+use crate::tls::TlsStorage;
+
+mod certs;
+mod check;
+mod issue;
+
+#[derive(Parser)]
+#[command(name = "swarmly", version, about = "Reverse proxy for Docker Swarm")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Runs the proxy, the default without a subcommand.
+    Serve,
+    /// Prints the routes Docker discovery and the routes file produce right now.
+    Routes,
+    /// Manages certificates in the configured storage.
+    #[command(subcommand)]
+    Certs(CertsCommand),
+    /// Forces an ACME order for a domain, even if its certificate is fresh.
+    Issue {
+        domain: String,
+        /// ACME provider to try first, like the `swarmly.acme.provider` label.
+        #[arg(long)]
+        provider: Option<String>,
+    },
+    /// Validates the environment and the labels of discovered services.
+    Check,
+}
+
+#[derive(Subcommand)]
+pub enum CertsCommand {
+    /// Lists stored certificates with their expiry.
+    List,
+    /// Prints subject, names, issuer and validity of a certificate.
+    Show { domain: String },
+    /// Writes the key and chain as PEM, to stdout without `--out`.
+    Export {
+        domain: String,
+        /// Directory for `<domain>.key` and `<domain>.crt`.
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Stores a certificate obtained elsewhere.
+    Import {
+        domain: String,
+        /// PEM file with the leaf certificate followed by the intermediates.
+        #[arg(long)]
+        cert: PathBuf,
+        /// PEM file with the private key.
+        #[arg(long)]
+        key: PathBuf,
+    },
+    /// Removes a certificate, it is issued again on the next renewal check.
+    Delete { domain: String },
+    /// Removes a certificate revoked at its CA, other nodes stop serving it at once.
+    Revoke { domain: String },
+}
+
+/// Runs a command other than `serve`.
+pub fn run(command: Command) -> ExitCode {
+    // stdout belongs to the command output
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(Level::WARN)
+        .with_writer(std::io::stderr)
+        .finish();
+
+    tracing::subscriber::set_global_default(subscriber).unwrap();
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to build tokio runtime");
+
+    let result = runtime.block_on(async {
+        match command {
+            Command::Serve => unreachable!("serve doesn't run as a command"),
+            Command::Routes => routes().await,
+            Command::Certs(command) => certs::run(command).await,
+            Command::Issue { domain, provider } => issue::run(&domain, provider.as_deref()).await,
+            Command::Check => check::run().await,
+        }
+    });
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {err:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn routes() -> anyhow::Result<()> {
+    let mut routes = provider::from_env()?.update().await?;
+    routes.sort_by(|a, b| a.0.cmp(&b.0));
+
+    if routes.is_empty() {
+        println!("no routes discovered");
+        return Ok(());
+    }
+
+    let width = routes
+        .iter()
+        .map(|(d, _)| d.len())
+        .max()
+        .unwrap_or(0)
+        .max(6);
+    println!("{:width$}  {:3}  BACKENDS", "DOMAIN", "TLS");
+
+    for (domain, config) in routes {
+        let backends: Vec<_> = config.addrs.iter().map(|a| a.to_string()).collect();
+        let tls = if config.tls { "yes" } else { "no" };
+        println!("{domain:width$}  {tls:3}  {}", backends.join(", "));
+    }
+
+    Ok(())
+}
+
+async fn redis() -> anyhow::Result<Option<RedisClient>> {
+    RedisClient::from_env()
+        .await
+        .context("failed to connect to redis")
+}
+
+/// The storage the server uses, redis if `REDIS_URL` is set.
+async fn storage() -> anyhow::Result<TlsStorage> {
+    let redis = redis().await?;
+    let events = redis.clone().map(EventBus::new);
+
+    TlsStorage::from_env(redis.zip(events))
+}
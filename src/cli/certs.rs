@@ -0,0 +1,173 @@
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use openssl::nid::Nid;
+use openssl::x509::X509NameRef;
+use std::io::Write;
+use std::path::Path;
+
+use super::CertsCommand;
+use crate::tls::{Certificate, TlsStorage};
+
+pub async fn run(command: CertsCommand) -> anyhow::Result<()> {
+    let mut storage = super::storage().await?;
+
+    match command {
+        CertsCommand::List => list(&mut storage).await,
+        CertsCommand::Show { domain } => show(get(&mut storage, &domain).await?),
+        CertsCommand::Export { domain, out } => {
+            export(get(&mut storage, &domain).await?, &domain, out.as_deref())
+        }
+        CertsCommand::Import { domain, cert, key } => {
+            import(&mut storage, &domain, &cert, &key).await
+        }
+        CertsCommand::Delete { domain } => {
+            if !storage.delete(&domain).await? {
+                anyhow::bail!("no certificate stored for {domain}");
+            }
+            println!("deleted certificate for {domain}");
+            Ok(())
+        }
+        CertsCommand::Revoke { domain } => {
+            if !storage.revoke(&domain).await? {
+                anyhow::bail!("no certificate stored for {domain}");
+            }
+            println!("removed revoked certificate for {domain}");
+            Ok(())
+        }
+    }
+}
+
+async fn get<'a>(storage: &'a mut TlsStorage, domain: &str) -> anyhow::Result<&'a Certificate> {
+    storage
+        .get(domain)
+        .await?
+        .with_context(|| format!("no certificate stored for {domain}"))
+}
+
+async fn list(storage: &mut TlsStorage) -> anyhow::Result<()> {
+    let domains = storage.list().await?;
+
+    if domains.is_empty() {
+        println!("no certificates stored");
+        return Ok(());
+    }
+
+    let width = domains.iter().map(String::len).max().unwrap_or(0).max(6);
+    println!("{:width$}  EXPIRES", "DOMAIN");
+
+    for domain in domains {
+        let expires = match storage.get(&domain).await {
+            Ok(Some(cert)) => cert
+                .expires_at()
+                .and_then(|at| DateTime::<Utc>::from_timestamp(at, 0))
+                .map(|at| at.to_rfc3339())
+                .unwrap_or_else(|| "unknown".to_owned()),
+            Ok(None) => "missing".to_owned(),
+            Err(err) => format!("unreadable: {err:#}"),
+        };
+        println!("{domain:width$}  {expires}");
+    }
+
+    Ok(())
+}
+
+fn show(cert: &Certificate) -> anyhow::Result<()> {
+    let leaf = cert.certificate();
+
+    let names: Vec<_> = leaf
+        .subject_alt_names()
+        .into_iter()
+        .flatten()
+        .filter_map(|name| name.dnsname().map(str::to_owned))
+        .collect();
+
+    println!("subject:    {}", common_name(leaf.subject_name()));
+    println!("names:      {}", names.join(", "));
+    println!("issuer:     {}", common_name(leaf.issuer_name()));
+    println!("not before: {}", leaf.not_before());
+    println!("not after:  {}", leaf.not_after());
+    println!(
+        "ordered:    {}",
+        DateTime::<Utc>::from_timestamp(cert.order_timestamp() as i64, 0)
+            .map(|at| at.to_rfc3339())
+            .unwrap_or_else(|| "unknown".to_owned())
+    );
+    println!("chain:      {} intermediate(s)", cert.chain().len());
+
+    Ok(())
+}
+
+fn common_name(name: &X509NameRef) -> String {
+    name.entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|entry| entry.data().as_utf8().ok())
+        .map(|cn| cn.to_string())
+        .unwrap_or_else(|| "-".to_owned())
+}
+
+fn export(cert: &Certificate, domain: &str, out: Option<&Path>) -> anyhow::Result<()> {
+    let key = cert.private_key_pem()?;
+    let chain = cert.chain_pem()?;
+
+    let Some(dir) = out else {
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(&key)?;
+        stdout.write_all(&chain)?;
+        return Ok(());
+    };
+
+    let key_path = dir.join(format!("{domain}.key"));
+    let cert_path = dir.join(format!("{domain}.crt"));
+
+    write_private(&key_path, &key)
+        .with_context(|| format!("failed to write {}", key_path.display()))?;
+    std::fs::write(&cert_path, &chain)
+        .with_context(|| format!("failed to write {}", cert_path.display()))?;
+
+    println!("wrote {} and {}", key_path.display(), cert_path.display());
+    Ok(())
+}
+
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(contents)
+}
+
+async fn import(
+    storage: &mut TlsStorage,
+    domain: &str,
+    cert_path: &Path,
+    key_path: &Path,
+) -> anyhow::Result<()> {
+    let cert_pem = std::fs::read(cert_path)
+        .with_context(|| format!("failed to read {}", cert_path.display()))?;
+    let key_pem = std::fs::read(key_path)
+        .with_context(|| format!("failed to read {}", key_path.display()))?;
+
+    // shown as the issue date, renewal only looks at notAfter
+    let probe = Certificate::new(&key_pem, &cert_pem, 0)?;
+    let issued = probe
+        .not_before()
+        .context("certificate has an invalid notBefore")?;
+
+    if !probe
+        .certificate()
+        .public_key()
+        .is_ok_and(|public| probe.private_key().public_eq(&public))
+    {
+        anyhow::bail!("private key does not match the certificate");
+    }
+
+    let cert = Certificate::new(&key_pem, &cert_pem, issued.max(0) as u64)?;
+    storage.set(domain, cert, None).await?;
+
+    println!("imported certificate for {domain}");
+    Ok(())
+}
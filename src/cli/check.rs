@@ -0,0 +1,109 @@
+use crate::config::provider::docker::DockerConfig;
+use crate::config::provider::file::FileConfig;
+use crate::config::provider::{ConfigProvider, GuardedConfig, Hsts};
+use crate::proxy::Route;
+use crate::proxy::access_log::AccessLog;
+use crate::proxy::request_id::RequestIds;
+use crate::tls::{AcmeResolver, ClientAuthPolicy, TlsStorage, profile_from_env};
+
+/// Runs every check and reports each, fails if any of them failed.
+pub async fn run() -> anyhow::Result<()> {
+    let mut failed = 0;
+    let mut report = |name: &str, result: anyhow::Result<String>| match result {
+        Ok(note) if note.is_empty() => println!("ok      {name}"),
+        Ok(note) => println!("ok      {name}: {note}"),
+        Err(err) => {
+            failed += 1;
+            println!("FAILED  {name}: {err:#}");
+        }
+    };
+
+    report(
+        "acme",
+        AcmeResolver::from_env().map(|acme| match acme {
+            Some(_) => String::new(),
+            None => "disabled, ACME_EMAIL is not set".to_owned(),
+        }),
+    );
+    report("tls profile", profile_from_env().map(|_| String::new()));
+    report("hsts", Hsts::from_env().map(|_| String::new()));
+    report("access log", AccessLog::from_env().map(|_| String::new()));
+    report(
+        "trusted proxies",
+        RequestIds::from_env().map(|_| String::new()),
+    );
+
+    report(
+        "route drop guard",
+        GuardedConfig::from_env(()).map(|_| String::new()),
+    );
+
+    report(
+        "redis",
+        super::redis().await.map(|redis| match redis {
+            Some(_) => "connected".to_owned(),
+            None => "disabled, REDIS_URL is not set".to_owned(),
+        }),
+    );
+
+    // opened without redis, only the keys can fail
+    report(
+        "encryption keys",
+        TlsStorage::from_env(None).map(|_| String::new()),
+    );
+
+    let mut routes = Vec::new();
+
+    if let Some(file) = FileConfig::from_env() {
+        match file.load().await {
+            Ok((value, skipped)) => {
+                report("routes file", Ok(format!("{} route(s)", value.len())));
+                routes.extend(value);
+
+                for (domain, err) in skipped {
+                    report(&format!("route {domain}"), Err(err));
+                }
+            }
+            Err(err) => report("routes file", Err(err)),
+        }
+    }
+
+    match DockerConfig::new() {
+        Ok(docker) => {
+            match docker.update().await {
+                Ok(value) => {
+                    report("docker", Ok(format!("{} route(s) discovered", value.len())));
+                    routes.extend(value);
+                }
+                Err(err) => report("docker", Err(err)),
+            }
+
+            for (domain, err) in docker.invalid_services().await {
+                report(&format!("labels of {domain}"), Err(err));
+            }
+        }
+        Err(err) => report("docker", Err(err)),
+    }
+
+    for (domain, config) in &routes {
+        let client_auth = match &config.options.client_auth {
+            Some(client_auth) => ClientAuthPolicy::load(client_auth).map(|_| ()),
+            None => Ok(()),
+        };
+
+        report(
+            &format!("route {domain}"),
+            Route::load(config).and(client_auth).map(|_| String::new()),
+        );
+    }
+
+    summary(failed)
+}
+
+fn summary(failed: usize) -> anyhow::Result<()> {
+    match failed {
+        0 => Ok(()),
+        1 => anyhow::bail!("1 check failed"),
+        n => anyhow::bail!("{n} checks failed"),
+    }
+}
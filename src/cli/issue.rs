@@ -0,0 +1,36 @@
+use anyhow::Context;
+use std::path::PathBuf;
+
+use crate::tls::{AcmeChallengeService, AcmeResolver, IssuanceLock, data_dir};
+
+/// Orders a certificate for `domain` under the issuance lock.
+///
+/// The challenges are answered by the running proxy, which finds them in
+/// redis or in the shared `DATA_DIR`.
+pub async fn run(domain: &str, provider: Option<&str>) -> anyhow::Result<()> {
+    let acme = AcmeResolver::from_env()
+        .context("failed to create acme resolver from env")?
+        .context("ACME_EMAIL is not set")?;
+
+    let redis = super::redis().await?;
+    let mut storage = super::storage().await?;
+    let service = AcmeChallengeService::new(redis.clone());
+
+    let lock_dir = PathBuf::from(data_dir()).join("locks");
+
+    let Some(lock) = IssuanceLock::acquire(redis.as_ref(), &lock_dir, domain).await? else {
+        anyhow::bail!("another node is issuing a certificate for {domain}");
+    };
+
+    eprintln!("ordering certificate for {domain}");
+
+    let stored = match acme.issue_cert(domain, provider, &service).await {
+        Ok(cert) => storage.set(domain, cert, lock.token()).await,
+        Err(err) => Err(err.context(format!("failed to issue cert for {domain}"))),
+    };
+    lock.release().await;
+    stored?;
+
+    println!("issued certificate for {domain}");
+    Ok(())
+}
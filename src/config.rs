@@ -1,20 +1,101 @@
+use chrono::{DateTime, Utc};
 use pingora::server::ShutdownWatch;
 use pingora::services::background::BackgroundService;
-use std::time::Duration;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 use self::provider::ConfigProvider;
 use crate::proxy::Gateway;
+use crate::{errors, metrics};
 
 pub mod provider;
 
+/// Time between refreshes, unless the provider reports a change earlier.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Longest wait between retries while refreshes keep failing.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
 pub struct ConfigRefresher<P> {
     provider: P,
     gateway: Gateway,
+    status: ConfigStatus,
+}
+
+/// Outcome of the latest provider refresh, shared with the admin api.
+#[derive(Clone, Default)]
+pub struct ConfigStatus {
+    inner: Arc<RwLock<Option<RefreshResult>>>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct RefreshResult {
+    pub at: DateTime<Utc>,
+    pub duration_ms: u64,
+    /// Error of the refresh, the previous config stays active.
+    pub error: Option<String>,
+    /// Services found by the provider, the last good ones on error.
+    pub services: Vec<DiscoveredService>,
+    pub last_success: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct DiscoveredService {
+    pub domain: String,
+    pub addrs: Vec<SocketAddr>,
+    pub tls: bool,
 }
 
 impl<P: ConfigProvider> ConfigRefresher<P> {
     pub fn new(provider: P, gateway: Gateway) -> Self {
-        Self { provider, gateway }
+        Self {
+            provider,
+            gateway,
+            status: ConfigStatus::default(),
+        }
+    }
+
+    pub fn status(&self) -> ConfigStatus {
+        self.status.clone()
+    }
+}
+
+impl ConfigStatus {
+    pub fn get(&self) -> Option<RefreshResult> {
+        self.inner.read().unwrap().clone()
+    }
+
+    fn record(&self, duration: Duration, result: &anyhow::Result<provider::Value>) {
+        let mut inner = self.inner.write().unwrap();
+        let now = Utc::now();
+        let previous = inner.take();
+        let previous_success = previous.as_ref().and_then(|r| r.last_success);
+
+        *inner = Some(match result {
+            Ok(value) => RefreshResult {
+                at: now,
+                duration_ms: duration.as_millis() as u64,
+                error: None,
+                services: value
+                    .iter()
+                    .map(|(domain, config)| DiscoveredService {
+                        domain: domain.clone(),
+                        addrs: config.addrs.clone(),
+                        tls: config.tls,
+                    })
+                    .collect(),
+                last_success: Some(now),
+            },
+            Err(err) => RefreshResult {
+                at: now,
+                duration_ms: duration.as_millis() as u64,
+                error: Some(format!("{err:#}")),
+                services: previous.map(|r| r.services).unwrap_or_default(),
+                last_success: previous_success,
+            },
+        });
     }
 }
 
@@ -24,23 +105,50 @@ impl<P: ConfigProvider + Send + Sync> BackgroundService for ConfigRefresher<P> {
         tracing::info!("preparing config update service..");
         tokio::time::sleep(Duration::from_secs(1)).await;
 
+        let mut failures: u32 = 0;
+
         loop {
             if shutdown.borrow().has_changed() {
                 tracing::info!("stopping config update service..");
                 break;
             }
 
-            let upstreams = match self.provider.update().await {
-                Ok(upstreams) => upstreams,
+            let start = Instant::now();
+            let result = self.provider.update().await;
+            metrics::CONFIG_REFRESH_DURATION.observe(start.elapsed().as_secs_f64());
+            self.status.record(start.elapsed(), &result);
+
+            // the gateway keeps routing the last good config on failure
+            let delay = match result {
+                Ok(upstreams) => {
+                    failures = 0;
+                    self.gateway.update(upstreams).await;
+                    REFRESH_INTERVAL
+                }
                 Err(err) => {
-                    tracing::error!("failed to update config provider: {err:?}");
-                    continue;
+                    failures = failures.saturating_add(1);
+                    metrics::CONFIG_REFRESH_ERRORS.inc();
+                    errors::record("config", None, &err);
+
+                    let delay = backoff(failures);
+                    tracing::error!(
+                        "failed to update config provider, retrying in {}s: {err:?}",
+                        delay.as_secs()
+                    );
+                    delay
                 }
             };
 
-            self.gateway.update(upstreams).await;
-
-            tokio::time::sleep(Duration::from_secs(10)).await;
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = self.provider.changed() => {}
+            }
         }
     }
 }
+
+/// Doubles the refresh interval with every failure in a row.
+fn backoff(failures: u32) -> Duration {
+    let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+    REFRESH_INTERVAL.saturating_mul(factor).min(MAX_BACKOFF)
+}
@@ -1,16 +1,45 @@
 use std::future::Future;
 use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
 
+pub use self::options::{
+    ClientAuth, ClientAuthMode, Hsts, RouteOptions, TlsProfile, TlsVersion, UpstreamTls,
+};
+
+pub use self::composite::CompositeConfig;
+pub use self::conflict::ConflictPolicy;
+pub use self::guard::GuardedConfig;
+
+mod composite;
+mod conflict;
 pub mod docker;
+pub mod file;
+mod guard;
+mod options;
 
 #[derive(Clone)]
 pub struct ServiceConfig {
     pub addrs: Vec<SocketAddr>,
     pub tls: bool,
+    pub options: RouteOptions,
+    /// `swarmly.priority`, decides conflicts under the `priority` policy.
+    pub priority: i64,
 }
 
 pub type Value = Vec<(String, ServiceConfig)>;
 
+/// Docker discovery, combined with the routes of `ROUTES_FILE` if set.
+pub fn from_env() -> anyhow::Result<GuardedConfig<CompositeConfig>> {
+    let mut provider = CompositeConfig::new(ConflictPolicy::from_env()?);
+
+    if let Some(file) = file::FileConfig::from_env() {
+        provider = provider.with("file", file);
+    }
+
+    GuardedConfig::from_env(provider.with("docker", docker::DockerConfig::new()?))
+}
+
 pub trait ConfigProvider {
     fn set_update_callback<F, Fut>(&self, callback: F)
     where
@@ -18,4 +47,48 @@ pub trait ConfigProvider {
         Fut: Future<Output = ()> + Send + 'static;
 
     fn update(&self) -> impl Future<Output = anyhow::Result<Value>> + Send;
+
+    /// Resolves once the source changed, so it is refreshed before the next
+    /// interval. Sources without change notifications never resolve.
+    fn changed(&self) -> impl Future<Output = ()> + Send {
+        std::future::pending()
+    }
+}
+
+type AsyncCallback =
+    dyn Fn(&Value) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync + 'static;
+
+/// Update callbacks registered on a provider.
+#[derive(Clone, Default)]
+pub struct Callbacks {
+    inner: Arc<RwLock<Vec<Box<AsyncCallback>>>>,
+}
+
+impl Callbacks {
+    pub fn push<F, Fut>(&self, callback: F)
+    where
+        F: Fn(Value) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let boxed = Box::new(move |value: &Value| {
+            Box::pin(callback(value.clone())) as Pin<Box<dyn Future<Output = ()> + Send>>
+        });
+
+        self.inner.write().unwrap().push(boxed);
+    }
+
+    /// Runs every callback with `value`, one after another.
+    pub async fn notify(&self, value: &Value) {
+        let futures: Vec<_> = self
+            .inner
+            .read()
+            .unwrap()
+            .iter()
+            .map(|cb| cb(value))
+            .collect();
+
+        for fut in futures {
+            fut.await;
+        }
+    }
 }
@@ -0,0 +1,107 @@
+use futures_util::future::{BoxFuture, FutureExt, select_all};
+use std::sync::Arc;
+
+use super::conflict::{Claim, ConflictPolicy, Resolver};
+use super::{Callbacks, ConfigProvider, Value};
+
+/// Combines the routes of several providers.
+///
+/// A domain routed by more than one provider is a conflict, resolved with the
+/// same policy Docker discovery applies between services. A failing source
+/// fails the whole update, so its routes aren't dropped while the others
+/// still answer.
+#[derive(Clone)]
+pub struct CompositeConfig {
+    sources: Arc<Vec<Source>>,
+    callbacks: Callbacks,
+    resolver: Resolver,
+}
+
+struct Source {
+    name: &'static str,
+    provider: Box<dyn DynProvider>,
+}
+
+/// Object safe part of [`ConfigProvider`].
+trait DynProvider: Send + Sync {
+    fn update(&self) -> BoxFuture<'_, anyhow::Result<Value>>;
+    fn changed(&self) -> BoxFuture<'_, ()>;
+}
+
+impl<P: ConfigProvider + Send + Sync> DynProvider for P {
+    fn update(&self) -> BoxFuture<'_, anyhow::Result<Value>> {
+        ConfigProvider::update(self).boxed()
+    }
+
+    fn changed(&self) -> BoxFuture<'_, ()> {
+        ConfigProvider::changed(self).boxed()
+    }
+}
+
+impl CompositeConfig {
+    pub fn new(policy: ConflictPolicy) -> Self {
+        Self {
+            sources: Arc::default(),
+            callbacks: Callbacks::default(),
+            resolver: Resolver::new("providers", policy),
+        }
+    }
+
+    pub fn with<P: ConfigProvider + Send + Sync + 'static>(
+        mut self,
+        name: &'static str,
+        provider: P,
+    ) -> Self {
+        Arc::get_mut(&mut self.sources)
+            .expect("sources are added before the provider is shared")
+            .push(Source {
+                name,
+                provider: Box::new(provider),
+            });
+        self
+    }
+
+    async fn merge(&self) -> anyhow::Result<Value> {
+        let mut claims = Vec::new();
+
+        for source in self.sources.iter() {
+            let value = source
+                .provider
+                .update()
+                .await
+                .map_err(|err| err.context(format!("{} provider failed", source.name)))?;
+
+            claims.extend(value.into_iter().map(|(domain, config)| {
+                let owner = source.name.to_owned();
+                (domain, Claim { owner, config })
+            }));
+        }
+
+        Ok(self.resolver.resolve(claims))
+    }
+}
+
+impl ConfigProvider for CompositeConfig {
+    fn set_update_callback<F, Fut>(&self, callback: F)
+    where
+        F: Fn(Value) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.callbacks.push(callback);
+    }
+
+    async fn update(&self) -> anyhow::Result<Value> {
+        let value = self.merge().await?;
+        self.callbacks.notify(&value).await;
+
+        Ok(value)
+    }
+
+    async fn changed(&self) {
+        if self.sources.is_empty() {
+            return std::future::pending().await;
+        }
+
+        select_all(self.sources.iter().map(|s| s.provider.changed())).await;
+    }
+}
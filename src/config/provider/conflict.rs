@@ -0,0 +1,385 @@
+use anyhow::Context;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use super::{ServiceConfig, Value};
+use crate::metrics;
+
+/// What happens when several services or providers route the same domain.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConflictPolicy {
+    /// Backends are combined if tls and every route option agree, otherwise
+    /// the domain is not routed.
+    Merge,
+    /// The highest `swarmly.priority` wins, claims sharing it are merged.
+    Priority,
+    /// A contested domain is not routed at all.
+    Reject,
+}
+
+/// A service, container group or provider routing a domain.
+pub struct Claim {
+    pub owner: String,
+    pub config: ServiceConfig,
+}
+
+/// Applies a [`ConflictPolicy`] and reports conflicts when they appear or go away.
+#[derive(Clone)]
+pub struct Resolver {
+    scope: &'static str,
+    policy: ConflictPolicy,
+    /// Active conflicts by domain, with their outcome and description.
+    reported: Arc<Mutex<HashMap<String, (&'static str, String)>>>,
+}
+
+impl ConflictPolicy {
+    /// `CONFLICT_POLICY`, defaults to `priority`.
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var("CONFLICT_POLICY") {
+            Ok(value) => Self::parse(&value).context("invalid CONFLICT_POLICY"),
+            Err(_) => Ok(Self::Priority),
+        }
+    }
+
+    fn parse(value: &str) -> anyhow::Result<Self> {
+        match value.trim() {
+            "merge" => Ok(Self::Merge),
+            "priority" => Ok(Self::Priority),
+            "reject" => Ok(Self::Reject),
+            other => anyhow::bail!("unknown policy {other}, expected merge, priority or reject"),
+        }
+    }
+}
+
+/// `swarmly.priority`, `0` when unset.
+pub fn priority_from_labels(labels: &HashMap<String, String>) -> anyhow::Result<i64> {
+    match labels.get("swarmly.priority").map(|p| p.trim()) {
+        Some(p) if !p.is_empty() => p
+            .parse()
+            .with_context(|| format!("failed to parse swarmly.priority {p} as integer")),
+        _ => Ok(0),
+    }
+}
+
+impl Resolver {
+    pub fn new(scope: &'static str, policy: ConflictPolicy) -> Self {
+        Self {
+            scope,
+            policy,
+            reported: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Resolves the claims of every domain into one route each.
+    pub fn resolve(&self, claims: Vec<(String, Claim)>) -> Value {
+        let mut by_domain: BTreeMap<String, Vec<Claim>> = BTreeMap::new();
+        for (domain, claim) in claims {
+            by_domain.entry(domain).or_default().push(claim);
+        }
+
+        let mut value = Vec::with_capacity(by_domain.len());
+        let mut conflicts = HashMap::new();
+
+        for (domain, mut claims) in by_domain {
+            if claims.len() == 1 {
+                value.push((domain, claims.pop().unwrap().config));
+                continue;
+            }
+
+            claims.sort_by(|a, b| a.owner.cmp(&b.owner));
+            let owners: Vec<_> = claims.iter().map(|c| c.owner.as_str()).collect();
+            let owners = owners.join(", ");
+
+            let (outcome, description, config) = self.resolve_domain(claims);
+            conflicts.insert(
+                domain.clone(),
+                (outcome, format!("{owners}: {description}")),
+            );
+
+            if let Some(config) = config {
+                value.push((domain, config));
+            }
+        }
+
+        self.report(conflicts);
+        value
+    }
+
+    fn resolve_domain(&self, claims: Vec<Claim>) -> (&'static str, String, Option<ServiceConfig>) {
+        match self.policy {
+            ConflictPolicy::Reject => ("rejected", "rejected by policy".to_owned(), None),
+            ConflictPolicy::Merge => merge(claims),
+            ConflictPolicy::Priority => {
+                let top = claims.iter().map(|c| c.config.priority).max().unwrap_or(0);
+                let (winners, _): (Vec<_>, Vec<_>) =
+                    claims.into_iter().partition(|c| c.config.priority == top);
+
+                match <[Claim; 1]>::try_from(winners) {
+                    Ok([winner]) => (
+                        "priority",
+                        format!("{} wins with priority {top}", winner.owner),
+                        Some(winner.config),
+                    ),
+                    Err(winners) => merge(winners),
+                }
+            }
+        }
+    }
+
+    fn report(&self, conflicts: HashMap<String, (&'static str, String)>) {
+        let mut reported = self.reported.lock().unwrap();
+
+        for (domain, (outcome, description)) in &conflicts {
+            if reported.get(domain).is_some_and(|(_, d)| d == description) {
+                continue;
+            }
+
+            if let Some((previous, _)) = reported.get(domain) {
+                metrics::ROUTE_CONFLICTS
+                    .remove_label_values(&[self.scope, domain, previous])
+                    .ok();
+            }
+
+            match *outcome {
+                "rejected" => {
+                    tracing::error!("{} conflict for {domain}, {description}", self.scope)
+                }
+                _ => tracing::warn!("{} conflict for {domain}, {description}", self.scope),
+            }
+            metrics::ROUTE_CONFLICTS
+                .with_label_values(&[self.scope, domain, outcome])
+                .set(1);
+        }
+
+        for (domain, (outcome, _)) in reported.iter() {
+            if !conflicts.contains_key(domain) {
+                tracing::info!("{} conflict for {domain} is gone", self.scope);
+                metrics::ROUTE_CONFLICTS
+                    .remove_label_values(&[self.scope, domain, outcome])
+                    .ok();
+            }
+        }
+
+        *reported = conflicts;
+    }
+}
+
+/// Combines the backends of claims that agree on everything else.
+fn merge(claims: Vec<Claim>) -> (&'static str, String, Option<ServiceConfig>) {
+    let mut claims = claims.into_iter();
+    let Some(first) = claims.next() else {
+        return ("rejected", "no claims".to_owned(), None);
+    };
+
+    let mut merged = first.config;
+    for claim in claims {
+        if claim.config.tls != merged.tls || claim.config.options != merged.options {
+            return (
+                "rejected",
+                format!(
+                    "{} and {} disagree on tls or route options, not routed",
+                    first.owner, claim.owner
+                ),
+                None,
+            );
+        }
+
+        for addr in claim.config.addrs {
+            if !merged.addrs.contains(&addr) {
+                merged.addrs.push(addr);
+            }
+        }
+    }
+
+    ("merged", "backends merged".to_owned(), Some(merged))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::provider::RouteOptions;
+    use prometheus::core::Collector;
+    use std::net::SocketAddr;
+
+    fn claim(owner: &str, addr: &str, priority: i64) -> Claim {
+        Claim {
+            owner: owner.to_owned(),
+            config: ServiceConfig {
+                addrs: vec![addr.parse().unwrap()],
+                tls: false,
+                options: RouteOptions::default(),
+                priority,
+            },
+        }
+    }
+
+    fn claims(domain: &str, claims: Vec<Claim>) -> Vec<(String, Claim)> {
+        claims.into_iter().map(|c| (domain.to_owned(), c)).collect()
+    }
+
+    fn addrs(value: &Value, domain: &str) -> Option<Vec<SocketAddr>> {
+        value
+            .iter()
+            .find(|(d, _)| d == domain)
+            .map(|(_, config)| config.addrs.clone())
+    }
+
+    /// Resolutions reported for `domain`, with their gauge value.
+    fn reported(domain: &str) -> Vec<(String, i64)> {
+        let mut found = Vec::new();
+        for family in metrics::ROUTE_CONFLICTS.collect() {
+            for metric in family.get_metric() {
+                let label = |name: &str| {
+                    metric
+                        .get_label()
+                        .iter()
+                        .find(|l| l.get_name() == name)
+                        .map(|l| l.get_value().to_owned())
+                };
+                if label("domain").as_deref() == Some(domain) {
+                    let value = metric.get_gauge().get_value() as i64;
+                    found.push((label("resolution").unwrap(), value));
+                }
+            }
+        }
+        found
+    }
+
+    #[test]
+    fn single_claim_is_not_a_conflict() {
+        let resolver = Resolver::new("test", ConflictPolicy::Reject);
+        let value = resolver.resolve(claims("single.test", vec![claim("a", "10.0.0.1:80", 0)]));
+
+        assert_eq!(
+            addrs(&value, "single.test"),
+            Some(vec!["10.0.0.1:80".parse().unwrap()])
+        );
+        assert!(reported("single.test").is_empty());
+    }
+
+    #[test]
+    fn reject_drops_contested_domain() {
+        let resolver = Resolver::new("test", ConflictPolicy::Reject);
+        let value = resolver.resolve(claims(
+            "reject.test",
+            vec![claim("a", "10.0.0.1:80", 5), claim("b", "10.0.0.2:80", 0)],
+        ));
+
+        assert_eq!(addrs(&value, "reject.test"), None);
+        assert_eq!(reported("reject.test"), [("rejected".to_owned(), 1)]);
+    }
+
+    #[test]
+    fn merge_combines_backends() {
+        let resolver = Resolver::new("test", ConflictPolicy::Merge);
+        let value = resolver.resolve(claims(
+            "merge.test",
+            vec![
+                claim("a", "10.0.0.1:80", 0),
+                claim("b", "10.0.0.2:80", 3),
+                claim("c", "10.0.0.1:80", 0),
+            ],
+        ));
+
+        assert_eq!(
+            addrs(&value, "merge.test"),
+            Some(vec![
+                "10.0.0.1:80".parse().unwrap(),
+                "10.0.0.2:80".parse().unwrap()
+            ])
+        );
+        assert_eq!(reported("merge.test"), [("merged".to_owned(), 1)]);
+    }
+
+    #[test]
+    fn merge_rejects_claims_that_disagree() {
+        let resolver = Resolver::new("test", ConflictPolicy::Merge);
+        let mut tls = claim("b", "10.0.0.2:443", 0);
+        tls.config.tls = true;
+
+        let value = resolver.resolve(claims(
+            "merge-disagree.test",
+            vec![claim("a", "10.0.0.1:80", 0), tls],
+        ));
+
+        assert_eq!(addrs(&value, "merge-disagree.test"), None);
+        assert_eq!(
+            reported("merge-disagree.test"),
+            [("rejected".to_owned(), 1)]
+        );
+    }
+
+    #[test]
+    fn priority_picks_highest() {
+        let resolver = Resolver::new("test", ConflictPolicy::Priority);
+        let value = resolver.resolve(claims(
+            "priority.test",
+            vec![claim("a", "10.0.0.1:80", 1), claim("b", "10.0.0.2:80", 10)],
+        ));
+
+        assert_eq!(
+            addrs(&value, "priority.test"),
+            Some(vec!["10.0.0.2:80".parse().unwrap()])
+        );
+        assert_eq!(reported("priority.test"), [("priority".to_owned(), 1)]);
+    }
+
+    #[test]
+    fn priority_tie_is_merged() {
+        let resolver = Resolver::new("test", ConflictPolicy::Priority);
+        let value = resolver.resolve(claims(
+            "tie.test",
+            vec![
+                claim("a", "10.0.0.1:80", 2),
+                claim("b", "10.0.0.2:80", 2),
+                claim("c", "10.0.0.3:80", 1),
+            ],
+        ));
+
+        assert_eq!(
+            addrs(&value, "tie.test"),
+            Some(vec![
+                "10.0.0.1:80".parse().unwrap(),
+                "10.0.0.2:80".parse().unwrap()
+            ])
+        );
+        assert_eq!(reported("tie.test"), [("merged".to_owned(), 1)]);
+    }
+
+    #[test]
+    fn priority_tie_that_disagrees_is_not_routed() {
+        let resolver = Resolver::new("test", ConflictPolicy::Priority);
+        let mut tls = claim("b", "10.0.0.2:443", 0);
+        tls.config.tls = true;
+
+        let value = resolver.resolve(claims(
+            "tie-disagree.test",
+            vec![claim("a", "10.0.0.1:80", 0), tls],
+        ));
+
+        assert_eq!(addrs(&value, "tie-disagree.test"), None);
+        assert_eq!(reported("tie-disagree.test"), [("rejected".to_owned(), 1)]);
+    }
+
+    #[test]
+    fn report_follows_conflict_until_it_is_gone() {
+        let resolver = Resolver::new("test", ConflictPolicy::Priority);
+        let domain = "transitions.test";
+
+        resolver.resolve(claims(
+            domain,
+            vec![claim("a", "10.0.0.1:80", 1), claim("b", "10.0.0.2:80", 0)],
+        ));
+        assert_eq!(reported(domain), [("priority".to_owned(), 1)]);
+
+        // the outcome changes, the old series is replaced
+        resolver.resolve(claims(
+            domain,
+            vec![claim("a", "10.0.0.1:80", 0), claim("b", "10.0.0.2:80", 0)],
+        ));
+        assert_eq!(reported(domain), [("merged".to_owned(), 1)]);
+
+        resolver.resolve(claims(domain, vec![claim("a", "10.0.0.1:80", 0)]));
+        assert!(reported(domain).is_empty());
+    }
+}
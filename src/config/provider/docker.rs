@@ -1,68 +1,118 @@
 use anyhow::Context;
-use bollard::Docker;
+use bollard::models::{
+    EndpointPortConfigProtocolEnum, EndpointPortConfigPublishModeEnum, EndpointSpecModeEnum,
+};
 use bollard::query_parameters::{
-    InspectContainerOptions, InspectNetworkOptions, ListServicesOptions,
+    InspectNetworkOptions, ListContainersOptions, ListServicesOptions,
 };
+use bollard::{API_DEFAULT_VERSION, Docker};
 use std::collections::{BTreeSet, HashMap};
-use std::net::{IpAddr, SocketAddr};
-use std::pin::Pin;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
 use std::str::FromStr;
-use std::sync::{Arc, RwLock};
 
 use self::container::Container;
-use super::{ConfigProvider, ServiceConfig, Value};
+use self::network::Networks;
+use self::tasks::{TaskAddrs, Tasks};
+use super::conflict::{Claim, ConflictPolicy, Resolver, priority_from_labels};
+use super::{Callbacks, ConfigProvider, RouteOptions, ServiceConfig, Value};
+use crate::telemetry;
 
 mod container;
-
-type AsyncCallback =
-    dyn Fn(&Value) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync + 'static;
+mod network;
+mod tasks;
+
+/// Per request timeout, so a hanging remote engine doesn't stall the refresh for long.
+const TIMEOUT_SECS: u64 = 30;
+
+/// Where published ports are reached in host network mode.
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+/// How swarm services are addressed.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Endpoint {
+    /// The virtual ip, swarm balances between the tasks.
+    Vip,
+    /// Every running task, so swarmly balances and health checks the replicas.
+    Tasks,
+}
 
 #[derive(Clone)]
 pub struct DockerConfig {
     client: Docker,
-    callbacks: Arc<RwLock<Vec<Box<AsyncCallback>>>>,
+    callbacks: Callbacks,
+    resolver: Resolver,
+    /// `SWARMLY_ENDPOINT`, services can override it with `swarmly.endpoint`.
+    endpoint: Endpoint,
+    /// `DOCKER_REMOTE`, the engine runs on another machine and its published
+    /// ports aren't on localhost.
+    remote: bool,
+}
+
+impl Endpoint {
+    fn parse(value: &str) -> anyhow::Result<Self> {
+        match value.trim() {
+            "vip" => Ok(Self::Vip),
+            "tasks" => Ok(Self::Tasks),
+            other => anyhow::bail!("unknown endpoint {other}, expected vip or tasks"),
+        }
+    }
 }
 
 impl DockerConfig {
     pub fn new() -> anyhow::Result<Self> {
-        let client =
-            Docker::connect_with_socket_defaults().context("failed to connect to docker")?;
-        let callbacks = Arc::new(RwLock::new(Vec::default()));
+        let client = connect().context("failed to connect to docker")?;
+        let resolver = Resolver::new("docker", ConflictPolicy::from_env()?);
+        let endpoint = match std::env::var("SWARMLY_ENDPOINT") {
+            Ok(value) => Endpoint::parse(&value).context("invalid SWARMLY_ENDPOINT")?,
+            Err(_) => Endpoint::Vip,
+        };
 
-        Ok(Self { client, callbacks })
-    }
+        let remote = parse_remote(std::env::var("DOCKER_REMOTE").ok().as_deref())
+            .context("invalid DOCKER_REMOTE")?;
 
-    async fn get_current_networks(&self) -> anyhow::Result<Vec<String>> {
-        let hostname = std::env::var("HOSTNAME").context("missing hostname env var")?;
+        Ok(Self {
+            client,
+            callbacks: Callbacks::default(),
+            resolver,
+            endpoint,
+            remote,
+        })
+    }
 
-        let container = self
-            .client
-            .inspect_container(&hostname, None::<InspectContainerOptions>)
-            .await
-            .context("failed to get container")?;
-
-        let ids = container
-            .network_settings
-            .context("container does not have network settings")?
-            .networks
-            .unwrap_or_default()
-            .into_values()
-            .filter_map(|e| e.network_id)
-            .collect();
+    /// Swarm services whose labels don't parse, discovery skips them.
+    pub async fn invalid_services(&self) -> Vec<(String, anyhow::Error)> {
+        let Ok(services) = self.client.list_services(None::<ListServicesOptions>).await else {
+            return Vec::new();
+        };
 
-        Ok(ids)
+        services
+            .iter()
+            .filter_map(|service| {
+                let labels = service.spec.as_ref()?.labels.as_ref()?;
+                let domain = labels
+                    .get("swarmly.domain")
+                    .map(|d| d.trim())
+                    .filter(|d| !d.is_empty())?;
+
+                route_labels(labels)
+                    .err()
+                    .map(|err| (domain.to_owned(), err))
+            })
+            .collect()
     }
 
-    async fn try_swarm_update(&self) -> anyhow::Result<Value> {
-        let network_ids = self.get_current_networks().await?;
-
+    async fn try_swarm_update(&self, networks: &Networks) -> anyhow::Result<Value> {
         let services = self
             .client
             .list_services(None::<ListServicesOptions>)
             .await
             .context("failed to list swarm services")?;
 
-        let mut result: HashMap<String, (Vec<SocketAddr>, bool)> = HashMap::new();
+        let tasks = Tasks::load(&self.client).await?;
+        let mut claims = Vec::new();
+        // only loaded if a service is addressed by task
+        let mut task_addrs = None;
 
         for service in services {
             let labels = service.spec.as_ref().and_then(|s| s.labels.as_ref());
@@ -87,57 +137,168 @@ impl DockerConfig {
                 .map(|v| v.trim() == "true")
                 .unwrap_or(false);
 
-            let vips = service
-                .endpoint
+            let healthcheck = labels
+                .get("swarmly.healthcheck")
+                .map(|v| v.trim() != "false")
+                .unwrap_or(true);
+
+            let (options, priority, endpoint_label) = match route_labels(labels) {
+                Ok(parsed) => parsed,
+                Err(err) => {
+                    tracing::warn!("skipping service for domain({domain}): {err:?}");
+                    continue;
+                }
+            };
+
+            let owner = service
+                .spec
                 .as_ref()
-                .and_then(|e| e.virtual_ips.as_ref());
+                .and_then(|s| s.name.clone())
+                .or_else(|| service.id.clone())
+                .unwrap_or_default();
+
+            let task_ids = tasks.ids(service.id.as_deref().unwrap_or_default(), !healthcheck);
+            if healthcheck && task_ids.is_empty() {
+                tracing::debug!("{owner} has no running tasks, skipping");
+                continue;
+            }
 
-            let addrs: Vec<SocketAddr> = vips
-                .into_iter()
-                .flatten()
-                .filter(|vip| {
-                    vip.network_id
-                        .as_ref()
-                        .map(|id| network_ids.contains(id))
-                        .unwrap_or(false)
-                })
-                .filter_map(|vip| vip.addr.as_ref().and_then(|a| parse_vip_ip(a)))
-                .map(|ip| SocketAddr::new(ip, port))
-                .collect();
+            // dnsrr services have no vip to route to
+            let dnsrr = service
+                .spec
+                .as_ref()
+                .and_then(|s| s.endpoint_spec.as_ref())
+                .and_then(|e| e.mode)
+                == Some(EndpointSpecModeEnum::DNSRR);
+            let mode = match endpoint_label {
+                _ if dnsrr => Endpoint::Tasks,
+                Some(mode) => mode,
+                None => self.endpoint,
+            };
+
+            let endpoint = service.endpoint.as_ref();
+            let vips = |network_ids: &[String]| -> Vec<SocketAddr> {
+                endpoint
+                    .and_then(|e| e.virtual_ips.as_ref())
+                    .into_iter()
+                    .flatten()
+                    .filter(|vip| {
+                        vip.network_id
+                            .as_ref()
+                            .map(|id| network_ids.contains(id))
+                            .unwrap_or(false)
+                    })
+                    .filter_map(|vip| vip.addr.as_ref().and_then(|a| parse_vip_ip(a)))
+                    .map(|ip| SocketAddr::new(ip, port))
+                    .collect()
+            };
+
+            let addrs: Vec<SocketAddr> = match networks {
+                Networks::Attached(network_ids) if mode == Endpoint::Tasks => {
+                    let task_addrs = match &mut task_addrs {
+                        Some(task_addrs) => task_addrs,
+                        None => {
+                            task_addrs.insert(TaskAddrs::load(&self.client, network_ids).await?)
+                        }
+                    };
+                    let addrs: Vec<_> = task_ids
+                        .iter()
+                        .filter_map(|id| task_addrs.get(id))
+                        .map(|ip| SocketAddr::new(ip, port))
+                        .collect();
+
+                    // an engine not attached to the network doesn't see its tasks
+                    if !addrs.is_empty() || task_ids.is_empty() {
+                        addrs
+                    } else if dnsrr {
+                        tracing::warn!(
+                            "no task addresses found for dnsrr service {owner}, not routed"
+                        );
+                        addrs
+                    } else {
+                        tracing::warn!("no task addresses found for {owner}, routing to its vip");
+                        vips(network_ids)
+                    }
+                }
+                Networks::Attached(network_ids) => vips(network_ids),
+                // the routing mesh answers ingress ports on every node, host
+                // mode ports only where a task runs
+                Networks::Host => endpoint
+                    .and_then(|e| e.ports.as_ref())
+                    .into_iter()
+                    .flatten()
+                    .filter(|p| p.target_port == Some(i64::from(port)))
+                    .filter(|p| p.protocol != Some(EndpointPortConfigProtocolEnum::UDP))
+                    .filter(|p| {
+                        matches!(
+                            p.publish_mode,
+                            None | Some(EndpointPortConfigPublishModeEnum::INGRESS)
+                        )
+                    })
+                    .filter_map(|p| u16::try_from(p.published_port?).ok())
+                    .map(|published| SocketAddr::new(LOCALHOST, published))
+                    .collect(),
+            };
 
             if !addrs.is_empty() {
-                let entry = result.entry(domain).or_insert((Vec::new(), tls));
-                entry.0.extend(addrs);
+                let config = ServiceConfig {
+                    addrs,
+                    tls,
+                    options,
+                    priority,
+                };
+                claims.push((domain, Claim { owner, config }));
             }
         }
 
-        Ok(result
-            .into_iter()
-            .map(|(domain, (addrs, tls))| (domain, ServiceConfig { addrs, tls }))
-            .collect())
+        Ok(self.resolver.resolve(claims))
     }
 
-    async fn try_container_update(&self) -> anyhow::Result<Value> {
-        let network_ids = self.get_current_networks().await?;
-        let containers = self.get_containers_in_networks(&network_ids).await?;
+    async fn try_container_update(&self, networks: &Networks) -> anyhow::Result<Value> {
+        let containers = match networks {
+            Networks::Attached(ids) => self.get_containers_in_networks(ids).await?,
+            Networks::Host if self.remote => anyhow::bail!(
+                "containers of a remote engine (DOCKER_REMOTE=true) publish ports on that host, not on localhost, \
+                 attach swarmly to their networks with SWARMLY_NETWORKS instead"
+            ),
+            Networks::Host => self.get_published_containers().await?,
+        };
 
-        let mut result: HashMap<String, (Vec<SocketAddr>, bool)> = HashMap::new();
+        // replicas of one compose service are a single claim, so they only
+        // conflict with other services
+        let mut claims: HashMap<(String, String), ServiceConfig> = HashMap::new();
 
         containers.iter().for_each(|c| {
             let port = c.get_port().unwrap_or(80);
-            let addr = SocketAddr::new(c.get_ip_addr(), port);
-            let tls = c.get_tls();
+            let addr = match networks {
+                Networks::Attached(_) => SocketAddr::new(c.get_ip_addr(), port),
+                Networks::Host => match c.get_published(port) {
+                    Some(addr) => addr,
+                    None => {
+                        tracing::warn!("{} does not publish port {port}, skipping", c.get_owner());
+                        return;
+                    }
+                },
+            };
 
             c.get_domains_unchecked().iter().for_each(|d| {
-                let entry = result.entry(d.to_owned()).or_insert((Vec::new(), tls));
-                entry.0.push(addr);
+                let key = (d.to_owned(), c.get_owner().to_owned());
+                let entry = claims.entry(key).or_insert_with(|| ServiceConfig {
+                    addrs: Vec::new(),
+                    tls: c.get_tls(),
+                    options: c.get_options(),
+                    priority: c.get_priority(),
+                });
+                entry.addrs.push(addr);
             });
         });
 
-        Ok(result
+        let claims = claims
             .into_iter()
-            .map(|(domain, (addrs, tls))| (domain, ServiceConfig { addrs, tls }))
-            .collect())
+            .map(|((domain, owner), config)| (domain, Claim { owner, config }))
+            .collect();
+
+        Ok(self.resolver.resolve(claims))
     }
 
     async fn get_containers_in_networks(
@@ -174,12 +335,131 @@ impl DockerConfig {
                 .await
                 .context("failed to load container config")?
             {
+                if !container.is_ready() {
+                    tracing::debug!("{} is not ready, skipping", container.get_owner());
+                    continue;
+                }
                 filtered_containers.insert(container);
             }
         }
 
         Ok(filtered_containers)
     }
+
+    /// Running containers with a `swarmly.domain` label, on any network.
+    async fn get_published_containers(&self) -> anyhow::Result<BTreeSet<Container>> {
+        let filters = HashMap::from([("label".to_owned(), vec!["swarmly.domain".to_owned()])]);
+        let summaries = self
+            .client
+            .list_containers(Some(ListContainersOptions {
+                filters: Some(filters),
+                ..Default::default()
+            }))
+            .await
+            .context("failed to list containers")?;
+
+        let mut containers = BTreeSet::new();
+
+        for id in summaries.into_iter().filter_map(|c| c.id) {
+            let mut container = Container::new(id, &LOCALHOST.to_string())?;
+            if container
+                .load_config(&self.client)
+                .await
+                .context("failed to load container config")?
+            {
+                if !container.is_ready() {
+                    tracing::debug!("{} is not ready, skipping", container.get_owner());
+                    continue;
+                }
+                containers.insert(container);
+            }
+        }
+
+        Ok(containers)
+    }
+}
+
+/// `DOCKER_HOST`, over TLS with the client certificate in `DOCKER_CERT_PATH`,
+/// or the local socket if unset.
+fn connect() -> anyhow::Result<Docker> {
+    let var = |key: &str| {
+        std::env::var(key)
+            .ok()
+            .map(|v| v.trim().to_owned())
+            .filter(|v| !v.is_empty())
+    };
+
+    let client = match (var("DOCKER_HOST"), var("DOCKER_CERT_PATH")) {
+        (None, None) => Docker::connect_with_socket_defaults()?,
+        (None, Some(_)) => anyhow::bail!("DOCKER_CERT_PATH requires DOCKER_HOST"),
+        (Some(host), None) => Docker::connect_with_host(&host)
+            .map(|docker| docker.with_timeout(std::time::Duration::from_secs(TIMEOUT_SECS)))
+            .with_context(|| format!("unsupported DOCKER_HOST {host}"))?,
+        (Some(host), Some(dir)) => {
+            if host.starts_with("unix://") {
+                anyhow::bail!("DOCKER_CERT_PATH requires a tcp:// or https:// DOCKER_HOST");
+            }
+
+            // other dependencies enable a second rustls backend, so rustls
+            // can't pick a default on its own
+            let _ = rustls::crypto::ring::default_provider().install_default();
+
+            let dir = Path::new(&dir);
+            for file in ["key.pem", "cert.pem", "ca.pem"] {
+                if !dir.join(file).is_file() {
+                    anyhow::bail!("DOCKER_CERT_PATH {} has no {file}", dir.display());
+                }
+            }
+
+            Docker::connect_with_ssl(
+                &host,
+                &dir.join("key.pem"),
+                &dir.join("cert.pem"),
+                &dir.join("ca.pem"),
+                TIMEOUT_SECS,
+                API_DEFAULT_VERSION,
+            )?
+        }
+    };
+
+    Ok(client)
+}
+
+/// Whether the engine runs on another machine. `DOCKER_HOST` alone doesn't
+/// tell, a socket proxy next to swarmly is reached over tcp as well.
+fn parse_remote(value: Option<&str>) -> anyhow::Result<bool> {
+    match value.map(str::trim) {
+        Some("true") => Ok(true),
+        Some("false") | None => Ok(false),
+        Some(other) => anyhow::bail!("invalid value {other}, expected true or false"),
+    }
+}
+
+/// Swarm endpoints answer 503 on engines that aren't swarm managers, but
+/// also while a swarm has lost its quorum, only the message tells them apart.
+fn is_not_swarm_manager(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<bollard::errors::Error>(),
+        Some(bollard::errors::Error::DockerResponseServerError {
+            status_code: 503,
+            message,
+        }) if message.contains("not a swarm manager")
+    )
+}
+
+fn route_labels(
+    labels: &HashMap<String, String>,
+) -> anyhow::Result<(RouteOptions, i64, Option<Endpoint>)> {
+    let endpoint = match labels.get("swarmly.endpoint") {
+        Some(value) => Some(Endpoint::parse(value).context("invalid swarmly.endpoint")?),
+        None => None,
+    };
+
+    Ok((
+        RouteOptions::from_labels(labels)?,
+        priority_from_labels(labels)?,
+        endpoint,
+    ))
 }
 
 fn parse_vip_ip(addr: &str) -> Option<IpAddr> {
@@ -193,40 +473,48 @@ impl ConfigProvider for DockerConfig {
         F: Fn(Value) -> Fut + Send + Sync + 'static,
         Fut: Future<Output = ()> + Send + 'static,
     {
-        let boxed = Box::new(move |value: &Value| {
-            Box::pin(callback(value.clone())) as Pin<Box<dyn Future<Output = ()> + Send>>
-        });
-
-        self.callbacks.write().unwrap().push(boxed);
+        self.callbacks.push(callback);
     }
 
     async fn update(&self) -> anyhow::Result<Value> {
-        let value = match self.try_swarm_update().await {
-            Ok(v) => {
-                tracing::debug!("using docker swarm service discovery");
-                v
+        let value = telemetry::in_span("docker config update", Vec::new(), async {
+            let networks = network::current(&self.client).await?;
+
+            match self.try_swarm_update(&networks).await {
+                Ok(v) => {
+                    tracing::debug!("using docker swarm service discovery");
+                    Ok(v)
+                }
+                // any other failure keeps the last config, a swarm read as
+                // plain containers would lose its services
+                Err(err) if is_not_swarm_manager(&err) => {
+                    tracing::debug!(
+                        "swarm unavailable ({}), falling back to container mode",
+                        err
+                    );
+                    self.try_container_update(&networks).await
+                }
+                Err(err) => Err(err),
             }
-            Err(err) => {
-                tracing::debug!(
-                    "swarm unavailable ({}), falling back to container mode",
-                    err
-                );
-                self.try_container_update().await?
-            }
-        };
+        })
+        .await?;
 
-        let futures: Vec<_> = self
-            .callbacks
-            .read()
-            .unwrap()
-            .iter()
-            .map(|cb| cb(&value))
-            .collect();
-
-        for fut in futures {
-            fut.await;
-        }
+        self.callbacks.notify(&value).await;
 
         Ok(value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remote_is_explicit() {
+        assert!(!parse_remote(None).unwrap());
+        assert!(!parse_remote(Some("false")).unwrap());
+        assert!(parse_remote(Some(" true ")).unwrap());
+        assert!(parse_remote(Some("yes")).is_err());
+        assert!(parse_remote(Some("tcp://10.0.0.5:2375")).is_err());
+    }
+}
@@ -1,20 +1,35 @@
 use anyhow::Context;
 use bollard::Docker;
+use bollard::errors::Error;
+use bollard::models::{ContainerStateStatusEnum, HealthStatusEnum, PortMap};
 use bollard::query_parameters::InspectContainerOptions;
 use std::collections::HashMap;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::str::FromStr;
 
+use crate::config::provider::RouteOptions;
+use crate::config::provider::conflict::priority_from_labels;
+
 pub struct Container {
     id: String,
     ip_addr: IpAddr,
+    /// Host addresses of published ports by container port.
+    published: HashMap<u16, SocketAddr>,
+    /// Running and not failing or still waiting for its healthcheck.
+    ready: bool,
     config: Option<Config>,
 }
 
 pub struct Config {
+    /// Compose service or container name, replicas share it.
+    owner: String,
+    priority: i64,
     port: Option<u16>,
     tls: bool,
     domains: Vec<String>,
+    options: RouteOptions,
+    /// `swarmly.healthcheck`, route regardless of the Docker health status.
+    healthcheck: bool,
 }
 
 impl Container {
@@ -30,6 +45,8 @@ impl Container {
         Ok(Self {
             id,
             ip_addr,
+            published: HashMap::new(),
+            ready: false,
             config: None,
         })
     }
@@ -38,6 +55,14 @@ impl Container {
         self.ip_addr
     }
 
+    pub fn get_published(&self, port: u16) -> Option<SocketAddr> {
+        self.published.get(&port).copied()
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+
     pub fn get_domains_unchecked(&self) -> &[String] {
         &self
             .config
@@ -54,11 +79,58 @@ impl Container {
         self.config.as_ref().map(|c| c.tls).unwrap_or(false)
     }
 
+    pub fn get_owner(&self) -> &str {
+        self.config
+            .as_ref()
+            .map(|c| c.owner.as_str())
+            .unwrap_or(&self.id)
+    }
+
+    pub fn get_priority(&self) -> i64 {
+        self.config.as_ref().map(|c| c.priority).unwrap_or(0)
+    }
+
+    pub fn get_options(&self) -> RouteOptions {
+        self.config
+            .as_ref()
+            .map(|c| c.options.clone())
+            .unwrap_or_default()
+    }
+
     pub async fn load_config(&mut self, client: &Docker) -> anyhow::Result<bool> {
-        let inspect = client
+        let inspect = match client
             .inspect_container(&self.id, None::<InspectContainerOptions>)
             .await
-            .context("failed to inspect container")?;
+        {
+            Ok(inspect) => inspect,
+            // removed since its network listed it
+            Err(Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => return Ok(false),
+            Err(err) => return Err(err).context("failed to inspect container"),
+        };
+
+        let name = inspect
+            .name
+            .as_deref()
+            .map(|n| n.trim_start_matches('/').to_owned())
+            .unwrap_or_else(|| self.id.clone());
+
+        self.published = inspect
+            .network_settings
+            .as_ref()
+            .and_then(|n| n.ports.as_ref())
+            .map(published_ports)
+            .unwrap_or_default();
+
+        let state = inspect.state.as_ref();
+        let running = state.and_then(|s| s.status) == Some(ContainerStateStatusEnum::RUNNING);
+        // containers without a healthcheck are ready once running
+        let health = state.and_then(|s| s.health.as_ref()).and_then(|h| h.status);
+        let healthy = !matches!(
+            health,
+            Some(HealthStatusEnum::STARTING | HealthStatusEnum::UNHEALTHY)
+        );
 
         let labels = inspect
             .config
@@ -66,9 +138,18 @@ impl Container {
             .labels
             .unwrap_or_else(HashMap::default);
 
-        let config = Config::from_labels(labels).context("failed to parse config")?;
+        // one container with bad labels doesn't hold back discovery of the others
+        let config = match Config::from_labels(labels, name.clone()) {
+            Ok(config) => config,
+            Err(err) => {
+                tracing::warn!("skipping container {name}: {err:#}");
+                return Ok(false);
+            }
+        };
         let is_loaded = config.is_some();
 
+        let healthcheck = config.as_ref().is_some_and(|c| c.healthcheck);
+        self.ready = running && (healthy || !healthcheck);
         self.config = config;
 
         Ok(is_loaded)
@@ -76,7 +157,10 @@ impl Container {
 }
 
 impl Config {
-    pub fn from_labels(labels: HashMap<String, String>) -> anyhow::Result<Option<Self>> {
+    pub fn from_labels(
+        labels: HashMap<String, String>,
+        name: String,
+    ) -> anyhow::Result<Option<Self>> {
         let domain = match labels.get("swarmly.domain").map(|d| d.trim()) {
             Some(d) if !d.is_empty() => d.to_owned(),
             _ => return Ok(None),
@@ -95,14 +179,54 @@ impl Config {
             .map(|v| v.trim() == "true")
             .unwrap_or(false);
 
+        let options =
+            RouteOptions::from_labels(&labels).context("failed to parse route options")?;
+        let priority = priority_from_labels(&labels)?;
+        let healthcheck = labels
+            .get("swarmly.healthcheck")
+            .map(|v| v.trim() != "false")
+            .unwrap_or(true);
+
+        let owner = match (
+            labels.get("com.docker.compose.project"),
+            labels.get("com.docker.compose.service"),
+        ) {
+            (Some(project), Some(service)) => format!("{project}_{service}"),
+            _ => name,
+        };
+
         Ok(Some(Self {
+            owner,
+            priority,
             domains: vec![domain],
             port,
             tls,
+            options,
+            healthcheck,
         }))
     }
 }
 
+/// First binding of every published tcp port, wildcard binds are reached on localhost.
+fn published_ports(ports: &PortMap) -> HashMap<u16, SocketAddr> {
+    ports
+        .iter()
+        .filter_map(|(key, bindings)| {
+            let port = key.strip_suffix("/tcp")?.parse().ok()?;
+            let binding = bindings.as_ref()?.first()?;
+            let host_port = binding.host_port.as_ref()?.parse().ok()?;
+            let host_ip = binding
+                .host_ip
+                .as_ref()
+                .and_then(|ip| ip.parse::<IpAddr>().ok())
+                .filter(|ip| !ip.is_unspecified())
+                .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+            Some((port, SocketAddr::new(host_ip, host_port)))
+        })
+        .collect()
+}
+
 impl Eq for Container {}
 
 impl PartialEq for Container {
@@ -0,0 +1,146 @@
+use anyhow::Context;
+use bollard::Docker;
+use bollard::query_parameters::{InspectContainerOptions, InspectNetworkOptions, ListTasksOptions};
+use std::collections::HashMap;
+
+/// Where swarmly reaches its upstreams.
+pub enum Networks {
+    /// Overlay or bridge networks by id, upstreams are reached on their ips there.
+    Attached(Vec<String>),
+    /// Swarmly shares the host network or runs outside Docker, upstreams are
+    /// reached on their published ports.
+    Host,
+}
+
+/// `SWARMLY_NETWORKS` if set, otherwise the networks of swarmly's own
+/// container, found through its cgroup or mount table and then `HOSTNAME`.
+pub async fn current(client: &Docker) -> anyhow::Result<Networks> {
+    if let Some(names) = configured() {
+        return resolve(client, names).await;
+    }
+
+    let id = own_container_id()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .context("can't find the container swarmly runs in, set SWARMLY_NETWORKS")?;
+
+    let container = match client
+        .inspect_container(&id, None::<InspectContainerOptions>)
+        .await
+    {
+        Ok(container) => container,
+        // a remote manager doesn't know containers of other nodes, but it
+        // knows the task behind them
+        Err(err) => match task_networks(client, &id).await {
+            Ok(Some(networks)) => return resolve(client, networks).await,
+            _ => {
+                return Err(err).context(format!(
+                    "failed to get container {id}, set SWARMLY_NETWORKS if swarmly runs outside Docker"
+                ));
+            }
+        },
+    };
+
+    let networks = container
+        .network_settings
+        .context("container does not have network settings")?
+        .networks
+        .unwrap_or_default();
+
+    if networks.contains_key("host") {
+        return Ok(Networks::Host);
+    }
+
+    Ok(Networks::Attached(
+        networks
+            .into_values()
+            .filter_map(|e| e.network_id)
+            .collect(),
+    ))
+}
+
+fn configured() -> Option<Vec<String>> {
+    let value = std::env::var("SWARMLY_NETWORKS").ok()?;
+    let names: Vec<_> = value
+        .split(',')
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .map(str::to_owned)
+        .collect();
+
+    (!names.is_empty()).then_some(names)
+}
+
+/// Network ids of names or ids, the `host` network switches to published ports.
+async fn resolve(client: &Docker, names: Vec<String>) -> anyhow::Result<Networks> {
+    let mut ids = Vec::new();
+
+    for name in names {
+        let network = client
+            .inspect_network(&name, None::<InspectNetworkOptions>)
+            .await
+            .with_context(|| format!("failed to get {name} network"))?;
+
+        if network.driver.as_deref() == Some("host") {
+            return Ok(Networks::Host);
+        }
+        ids.extend(network.id);
+    }
+
+    Ok(Networks::Attached(ids))
+}
+
+/// Networks of the running swarm task whose container id starts with `id`.
+async fn task_networks(client: &Docker, id: &str) -> anyhow::Result<Option<Vec<String>>> {
+    let filters = HashMap::from([("desired-state".to_owned(), vec!["running".to_owned()])]);
+    let tasks = client
+        .list_tasks(Some(ListTasksOptions {
+            filters: Some(filters),
+        }))
+        .await
+        .context("failed to list swarm tasks")?;
+
+    let task = tasks.into_iter().find(|task| {
+        task.status
+            .as_ref()
+            .and_then(|s| s.container_status.as_ref())
+            .and_then(|c| c.container_id.as_ref())
+            .is_some_and(|container| container.starts_with(id))
+    });
+
+    Ok(task.map(|task| {
+        task.spec
+            .and_then(|s| s.networks)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|n| n.target)
+            .collect()
+    }))
+}
+
+/// Id of the container this process runs in, independent of its hostname.
+fn own_container_id() -> Option<String> {
+    // cgroup v1 names the container in the cgroup path
+    let cgroup = std::fs::read_to_string("/proc/self/cgroup").unwrap_or_default();
+    let from_cgroup = cgroup.lines().find_map(|line| {
+        line.rsplit('/').find_map(|segment| {
+            let segment = segment.strip_prefix("docker-").unwrap_or(segment);
+            let segment = segment.strip_suffix(".scope").unwrap_or(segment);
+            is_container_id(segment).then(|| segment.to_owned())
+        })
+    });
+
+    // cgroup v2 doesn't, but /etc/hostname and friends are mounted from
+    // /var/lib/docker/containers/<id>/
+    from_cgroup.or_else(|| {
+        let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").unwrap_or_default();
+        mountinfo.lines().find_map(|line| {
+            let (_, path) = line.split_once("/containers/")?;
+            let id = path.split('/').next()?;
+            is_container_id(id).then(|| id.to_owned())
+        })
+    })
+}
+
+fn is_container_id(value: &str) -> bool {
+    value.len() == 64 && value.bytes().all(|b| b.is_ascii_hexdigit())
+}
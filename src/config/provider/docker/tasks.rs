@@ -0,0 +1,171 @@
+use anyhow::Context;
+use bollard::Docker;
+use bollard::models::{ServiceInfo, TaskState};
+use bollard::query_parameters::{InspectNetworkOptions, ListTasksOptions};
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// Tasks swarm wants running, by service id.
+pub struct Tasks {
+    by_service: HashMap<String, Vec<Task>>,
+}
+
+struct Task {
+    id: String,
+    /// Started and, if the service has a healthcheck, healthy. Swarm keeps
+    /// tasks in `starting` until their first healthcheck passes.
+    running: bool,
+}
+
+/// Addresses of swarm tasks on the attached networks, by task id.
+///
+/// Tasks don't carry their network attachments in the api model, the
+/// addresses come from a verbose inspect of each network instead.
+pub struct TaskAddrs {
+    by_task: HashMap<String, IpAddr>,
+}
+
+impl Tasks {
+    pub async fn load(client: &Docker) -> anyhow::Result<Self> {
+        let filters = HashMap::from([("desired-state".to_owned(), vec!["running".to_owned()])]);
+        let tasks = client
+            .list_tasks(Some(ListTasksOptions {
+                filters: Some(filters),
+            }))
+            .await
+            .context("failed to list swarm tasks")?;
+
+        let mut by_service: HashMap<String, Vec<Task>> = HashMap::new();
+        for task in tasks {
+            let (Some(id), Some(service_id)) = (task.id, task.service_id) else {
+                continue;
+            };
+            let running = task.status.and_then(|s| s.state) == Some(TaskState::RUNNING);
+
+            by_service
+                .entry(service_id)
+                .or_default()
+                .push(Task { id, running });
+        }
+
+        Ok(Self { by_service })
+    }
+
+    /// Ids of the service's running tasks, or of every task meant to run
+    /// with `any_state`.
+    pub fn ids(&self, service_id: &str, any_state: bool) -> Vec<&str> {
+        self.by_service
+            .get(service_id)
+            .into_iter()
+            .flatten()
+            .filter(|task| any_state || task.running)
+            .map(|task| task.id.as_str())
+            .collect()
+    }
+}
+
+impl TaskAddrs {
+    pub async fn load(client: &Docker, network_ids: &[String]) -> anyhow::Result<Self> {
+        let options = InspectNetworkOptions {
+            verbose: true,
+            scope: Some("swarm".to_owned()),
+        };
+
+        let mut by_task = HashMap::new();
+
+        for id in network_ids {
+            let network = client
+                .inspect_network(id, Some(options.clone()))
+                .await
+                .with_context(|| format!("failed to get {id} network"))?;
+
+            for (service, info) in network.services.unwrap_or_default() {
+                let info: ServiceInfo = match serde_json::from_value(info) {
+                    Ok(info) => info,
+                    Err(err) => {
+                        tracing::debug!("unexpected service info for {service}: {err}");
+                        continue;
+                    }
+                };
+
+                by_task.extend(info.tasks.unwrap_or_default().into_iter().filter_map(|t| {
+                    let task_id = task_id(t.name.as_deref()?)?.to_owned();
+                    let ip = endpoint_ip(t.endpoint_ip.as_deref()?)?;
+                    Some((task_id, ip))
+                }));
+            }
+        }
+
+        Ok(Self { by_task })
+    }
+
+    pub fn get(&self, task_id: &str) -> Option<IpAddr> {
+        self.by_task.get(task_id).copied()
+    }
+}
+
+/// Task names are `<service>.<slot>.<task id>`, or `<service>.<node id>.<task id>`
+/// for global services.
+fn task_id(name: &str) -> Option<&str> {
+    let (_, id) = name.rsplit_once('.')?;
+    (!id.is_empty()).then_some(id)
+}
+
+/// Endpoint ips come with their prefix length, e.g. `10.0.1.5/24`.
+fn endpoint_ip(value: &str) -> Option<IpAddr> {
+    value.split('/').next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn task_id_is_the_last_segment() {
+        assert_eq!(task_id("web.1.q8wf1y0kq3vz"), Some("q8wf1y0kq3vz"));
+        assert_eq!(
+            task_id("stack_web.x2k0d9n3m1.q8wf1y0kq3vz"),
+            Some("q8wf1y0kq3vz")
+        );
+        assert_eq!(task_id("web"), None);
+        assert_eq!(task_id("web.1."), None);
+    }
+
+    #[test]
+    fn endpoint_ip_strips_the_prefix_length() {
+        assert_eq!(endpoint_ip("10.0.1.5/24"), "10.0.1.5".parse().ok());
+        assert_eq!(endpoint_ip("10.0.1.5"), "10.0.1.5".parse().ok());
+        assert_eq!(endpoint_ip("fd00::5/64"), "fd00::5".parse().ok());
+        assert_eq!(endpoint_ip(""), None);
+        assert_eq!(endpoint_ip("not-an-ip/24"), None);
+    }
+
+    fn tasks() -> Tasks {
+        let task = |id: &str, running| Task {
+            id: id.to_owned(),
+            running,
+        };
+
+        Tasks {
+            by_service: HashMap::from([(
+                "svc".to_owned(),
+                vec![task("a", true), task("b", false), task("c", true)],
+            )]),
+        }
+    }
+
+    #[test]
+    fn ids_only_returns_running_tasks() {
+        assert_eq!(tasks().ids("svc", false), ["a", "c"]);
+    }
+
+    #[test]
+    fn ids_returns_every_task_in_any_state() {
+        assert_eq!(tasks().ids("svc", true), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn ids_of_unknown_service_are_empty() {
+        assert!(tasks().ids("other", false).is_empty());
+    }
+}
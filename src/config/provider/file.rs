@@ -0,0 +1,273 @@
+use anyhow::Context;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use super::conflict::priority_from_labels;
+use super::{Callbacks, ConfigProvider, RouteOptions, ServiceConfig, Value};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Routes for upstreams outside Docker, read from the file at `ROUTES_FILE`.
+/// Files ending in `.toml` are read as TOML, anything else as YAML.
+///
+/// ```yaml
+/// routes:
+///   - domain: db-admin.example.com
+///     backends: ["10.0.3.20:8080"]
+///     labels:
+///       swarmly.tls.client_ca: admin_client_ca
+/// ```
+#[derive(Clone)]
+pub struct FileConfig {
+    path: PathBuf,
+    callbacks: Callbacks,
+    /// Modification time and length of the file at the last update.
+    seen: Arc<Mutex<Option<(SystemTime, u64)>>>,
+    /// Last addresses of every backend, used while its name doesn't resolve.
+    resolved: Arc<Mutex<HashMap<String, Vec<SocketAddr>>>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RoutesFile {
+    #[serde(default)]
+    routes: Vec<FileRoute>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileRoute {
+    domain: String,
+    /// `<ip>:<port>` or `<host>:<port>`, hostnames are resolved on every update.
+    backends: Vec<String>,
+    #[serde(default)]
+    tls: bool,
+    /// Per-route options, with the same `swarmly.*` keys as the Docker labels.
+    #[serde(default)]
+    labels: HashMap<String, serde_yaml::Value>,
+}
+
+impl FileConfig {
+    /// `None` unless `ROUTES_FILE` is set.
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var("ROUTES_FILE").ok()?;
+        let path = path.trim();
+        if path.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            path: PathBuf::from(path),
+            callbacks: Callbacks::default(),
+            seen: Arc::new(Mutex::new(None)),
+            resolved: Arc::default(),
+        })
+    }
+
+    async fn modified(&self) -> Option<(SystemTime, u64)> {
+        let metadata = tokio::fs::metadata(&self.path).await.ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    }
+
+    /// Routes of the file, and the domains of routes skipped for being invalid.
+    pub async fn load(&self) -> anyhow::Result<(Value, Vec<(String, anyhow::Error)>)> {
+        // recorded before reading, so a missing file isn't reported as changed over and over
+        *self.seen.lock().unwrap() = self.modified().await;
+        let contents = tokio::fs::read_to_string(&self.path)
+            .await
+            .with_context(|| format!("failed to read routes file {}", self.path.display()))?;
+
+        let file = RoutesFile::parse(&self.path, &contents)
+            .with_context(|| format!("failed to parse routes file {}", self.path.display()))?;
+
+        let mut domains = HashSet::new();
+        let mut value = Vec::with_capacity(file.routes.len());
+        let mut skipped = Vec::new();
+
+        for route in file.routes {
+            let domain = route.domain.trim().to_lowercase();
+            if domain.is_empty() {
+                anyhow::bail!("route without a domain in {}", self.path.display());
+            }
+            if !domains.insert(domain.clone()) {
+                anyhow::bail!(
+                    "domain {domain} is defined twice in {}",
+                    self.path.display()
+                );
+            }
+
+            // one bad route doesn't hold back the others, nor Docker discovery
+            match route.into_config(&self.resolved).await {
+                Ok(config) => value.push((domain, config)),
+                Err(err) => skipped.push((domain, err)),
+            }
+        }
+
+        Ok((value, skipped))
+    }
+}
+
+impl RoutesFile {
+    fn parse(path: &Path, contents: &str) -> anyhow::Result<Self> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Ok(toml::from_str(contents)?),
+            _ => Ok(serde_yaml::from_str(contents)?),
+        }
+    }
+}
+
+impl FileRoute {
+    async fn into_config(
+        self,
+        resolved: &Mutex<HashMap<String, Vec<SocketAddr>>>,
+    ) -> anyhow::Result<ServiceConfig> {
+        let labels = self
+            .labels
+            .into_iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    serde_yaml::Value::String(s) => s,
+                    serde_yaml::Value::Bool(b) => b.to_string(),
+                    serde_yaml::Value::Number(n) => n.to_string(),
+                    _ => anyhow::bail!("label {key} must be a string, number or boolean"),
+                };
+                Ok((key, value))
+            })
+            .collect::<anyhow::Result<HashMap<_, _>>>()?;
+
+        let options = RouteOptions::from_labels(&labels)?;
+        let priority = priority_from_labels(&labels)?;
+
+        if self.backends.is_empty() {
+            anyhow::bail!("route has no backends");
+        }
+
+        let mut addrs = Vec::new();
+        for backend in &self.backends {
+            let backend = backend.trim();
+            match tokio::net::lookup_host(backend).await {
+                Ok(found) => {
+                    let found: Vec<_> = found.collect();
+                    addrs.extend(&found);
+                    resolved.lock().unwrap().insert(backend.to_owned(), found);
+                }
+                Err(err) => match resolved.lock().unwrap().get(backend) {
+                    Some(last) => {
+                        tracing::warn!(
+                            "failed to resolve backend {backend}, keeping {last:?}: {err}"
+                        );
+                        addrs.extend(last);
+                    }
+                    None => tracing::warn!("failed to resolve backend {backend}, skipping: {err}"),
+                },
+            }
+        }
+
+        if addrs.is_empty() {
+            anyhow::bail!("none of its backends resolved");
+        }
+
+        Ok(ServiceConfig {
+            addrs,
+            tls: self.tls,
+            options,
+            priority,
+        })
+    }
+}
+
+impl ConfigProvider for FileConfig {
+    fn set_update_callback<F, Fut>(&self, callback: F)
+    where
+        F: Fn(Value) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.callbacks.push(callback);
+    }
+
+    async fn update(&self) -> anyhow::Result<Value> {
+        let (value, skipped) = self.load().await?;
+        for (domain, err) in skipped {
+            tracing::warn!(
+                "skipping route for {domain} in {}: {err:#}",
+                self.path.display()
+            );
+        }
+        self.callbacks.notify(&value).await;
+
+        Ok(value)
+    }
+
+    /// Polls the modification time, which also catches config files and
+    /// secrets that are replaced instead of written in place.
+    async fn changed(&self) {
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+
+            let current = self.modified().await;
+            if current != *self.seen.lock().unwrap() {
+                tracing::info!("routes file {} changed", self.path.display());
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const YAML: &str = r#"
+routes:
+  - domain: db-admin.example.com
+    backends: ["10.0.3.20:8080"]
+    labels:
+      swarmly.tls.client_ca: admin_client_ca
+      swarmly.priority: 5
+"#;
+
+    const TOML: &str = r#"
+[[routes]]
+domain = "db-admin.example.com"
+backends = ["10.0.3.20:8080"]
+
+[routes.labels]
+"swarmly.tls.client_ca" = "admin_client_ca"
+"swarmly.priority" = 5
+"#;
+
+    async fn config(file: RoutesFile) -> ServiceConfig {
+        let route = file.routes.into_iter().next().unwrap();
+        assert_eq!(route.domain, "db-admin.example.com");
+        route.into_config(&Mutex::default()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn reads_yaml_and_toml_alike() {
+        let yaml = config(RoutesFile::parse(Path::new("routes.yml"), YAML).unwrap()).await;
+        let toml = config(RoutesFile::parse(Path::new("routes.toml"), TOML).unwrap()).await;
+
+        for config in [yaml, toml] {
+            assert_eq!(config.addrs, ["10.0.3.20:8080".parse().unwrap()]);
+            assert_eq!(config.priority, 5);
+            assert!(!config.tls);
+        }
+    }
+
+    #[test]
+    fn picks_the_parser_by_extension() {
+        assert!(RoutesFile::parse(Path::new("routes.yaml"), TOML).is_err());
+        assert!(RoutesFile::parse(Path::new("routes.toml"), YAML).is_err());
+        assert!(RoutesFile::parse(Path::new("routes"), YAML).is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        let toml = "[[routes]]\ndomain = \"a.example.com\"\nbackends = []\nport = 80\n";
+        assert!(RoutesFile::parse(Path::new("routes.toml"), toml).is_err());
+    }
+}
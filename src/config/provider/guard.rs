@@ -0,0 +1,150 @@
+use anyhow::Context;
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+
+use super::{Callbacks, ConfigProvider, Value};
+use crate::metrics;
+
+/// Keeps the last good config and holds back updates that drop many domains
+/// at once, until the same routes come back on several refreshes in a row.
+///
+/// A half answering Docker API or a network that is briefly gone can look
+/// like most services disappeared, applying that would take their routes
+/// and certificates down.
+#[derive(Clone)]
+pub struct GuardedConfig<P> {
+    inner: P,
+    callbacks: Callbacks,
+    /// Largest share of domains, in percent, one update may drop.
+    max_drop: u8,
+    /// Refreshes a larger drop has to be seen in before it is applied.
+    confirmations: u32,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    last_good: Option<Value>,
+    /// Domains of the held back update and how often it was seen.
+    pending: Option<(BTreeSet<String>, u32)>,
+}
+
+impl<P> GuardedConfig<P> {
+    /// `MAX_ROUTE_DROP` percent, defaults to 50, and
+    /// `ROUTE_DROP_CONFIRMATIONS`, defaults to 3.
+    pub fn from_env(inner: P) -> anyhow::Result<Self> {
+        let max_drop = match std::env::var("MAX_ROUTE_DROP") {
+            Ok(value) => value
+                .trim()
+                .parse()
+                .ok()
+                .filter(|p| *p <= 100)
+                .with_context(|| format!("invalid MAX_ROUTE_DROP {value}, expected 0 to 100"))?,
+            Err(_) => 50,
+        };
+
+        let confirmations = match std::env::var("ROUTE_DROP_CONFIRMATIONS") {
+            Ok(value) => value
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid ROUTE_DROP_CONFIRMATIONS {value}"))?,
+            Err(_) => 3,
+        };
+
+        Ok(Self {
+            inner,
+            callbacks: Callbacks::default(),
+            max_drop,
+            confirmations,
+            state: Arc::default(),
+        })
+    }
+
+    /// The update to apply. While a drop is held back, the dropped domains
+    /// keep their last good routes and everything else is applied.
+    fn check(&self, value: Value) -> Value {
+        let mut state = self.state.lock().unwrap();
+
+        let Some(last_good) = &state.last_good else {
+            state.last_good = Some(value.clone());
+            return value;
+        };
+
+        let domains: BTreeSet<String> = value.iter().map(|(d, _)| d.clone()).collect();
+        let dropped: Vec<&str> = last_good
+            .iter()
+            .map(|(d, _)| d.as_str())
+            .filter(|d| !domains.contains(*d))
+            .collect();
+
+        let within_limit = dropped.len() * 100 <= usize::from(self.max_drop) * last_good.len();
+        if within_limit {
+            if state.pending.take().is_some() {
+                tracing::info!("held back config update is gone");
+            }
+            metrics::CONFIG_UPDATE_HELD.set(0);
+            state.last_good = Some(value.clone());
+            return value;
+        }
+
+        let seen = match &state.pending {
+            Some((pending, seen)) if *pending == domains => seen + 1,
+            _ => 1,
+        };
+
+        if seen >= self.confirmations {
+            tracing::warn!(
+                "applying config update dropping {} of {} domains, confirmed {seen} times: {}",
+                dropped.len(),
+                last_good.len(),
+                dropped.join(", ")
+            );
+            metrics::CONFIG_UPDATE_HELD.set(0);
+            state.pending = None;
+            state.last_good = Some(value.clone());
+            return value;
+        }
+
+        tracing::warn!(
+            "holding back config update dropping {} of {} domains ({seen}/{}): {}",
+            dropped.len(),
+            last_good.len(),
+            self.confirmations,
+            dropped.join(", ")
+        );
+        metrics::CONFIG_UPDATE_HELD.set(1);
+
+        let mut value = value;
+        value.extend(
+            last_good
+                .iter()
+                .filter(|(d, _)| !domains.contains(d))
+                .cloned(),
+        );
+
+        state.pending = Some((domains, seen));
+        state.last_good = Some(value.clone());
+        value
+    }
+}
+
+impl<P: ConfigProvider + Send + Sync> ConfigProvider for GuardedConfig<P> {
+    fn set_update_callback<F, Fut>(&self, callback: F)
+    where
+        F: Fn(Value) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.callbacks.push(callback);
+    }
+
+    async fn update(&self) -> anyhow::Result<Value> {
+        let value = self.check(self.inner.update().await?);
+        self.callbacks.notify(&value).await;
+
+        Ok(value)
+    }
+
+    async fn changed(&self) {
+        self.inner.changed().await
+    }
+}
@@ -0,0 +1,325 @@
+use anyhow::Context;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::tls;
+
+const SECRETS_DIR: &str = "/run/secrets";
+
+/// Per-route settings read from `swarmly.*` labels on top of domain, port and tls.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct RouteOptions {
+    pub acme_provider: Option<String>,
+    pub client_auth: Option<ClientAuth>,
+    pub upstream_tls: UpstreamTls,
+    pub tls_profile: Option<TlsProfile>,
+    pub hsts: Option<Hsts>,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct ClientAuth {
+    pub mode: ClientAuthMode,
+    pub ca_path: PathBuf,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ClientAuthMode {
+    Require,
+    Optional,
+}
+
+/// How swarmly connects to an upstream served over TLS (`swarmly.tls=true`).
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct UpstreamTls {
+    pub ca_path: Option<PathBuf>,
+    pub skip_verify: bool,
+    pub sni: Option<String>,
+    pub verify_hostname: Option<String>,
+    pub client_cert: Option<(PathBuf, PathBuf)>,
+}
+
+/// TLS versions and ciphers accepted from clients.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TlsProfile {
+    pub min_version: TlsVersion,
+    pub ciphers: Option<String>,
+    pub ciphersuites: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+/// `Strict-Transport-Security` header settings, `max_age == 0` disables the header.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Hsts {
+    pub max_age: u64,
+    pub include_subdomains: bool,
+    pub preload: bool,
+}
+
+impl RouteOptions {
+    pub fn from_labels(labels: &HashMap<String, String>) -> anyhow::Result<Self> {
+        let acme_provider = match labels.get("swarmly.acme.provider").map(|p| p.trim()) {
+            // only picks the CA, a bad value doesn't cost the domain its route
+            Some(p) if !p.is_empty() => match tls::provider_from_label(p) {
+                Ok(url) => Some(url),
+                Err(err) => {
+                    tracing::warn!(
+                        "ignoring swarmly.acme.provider, using the default provider: {err:#}"
+                    );
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        let client_auth = ClientAuth::from_labels(labels)?;
+        let upstream_tls = UpstreamTls::from_labels(labels)?;
+
+        let get = |key: &str| labels.get(key).map(|v| v.as_str());
+
+        let tls_profile = TlsProfile::parse(
+            get("swarmly.tls.profile"),
+            get("swarmly.tls.min_version"),
+            get("swarmly.tls.ciphers"),
+            None,
+        )?;
+
+        let hsts = Hsts::parse(
+            get("swarmly.hsts.max_age"),
+            get("swarmly.hsts.include_subdomains"),
+            get("swarmly.hsts.preload"),
+        )?;
+
+        Ok(Self {
+            acme_provider,
+            client_auth,
+            upstream_tls,
+            tls_profile,
+            hsts,
+        })
+    }
+}
+
+impl ClientAuth {
+    fn from_labels(labels: &HashMap<String, String>) -> anyhow::Result<Option<Self>> {
+        let ca = labels.get("swarmly.tls.client_ca").map(|c| c.trim());
+        let mode = labels.get("swarmly.tls.client_auth").map(|m| m.trim());
+
+        let ca_path = match (ca, mode) {
+            (Some(ca), _) if !ca.is_empty() => secret_path(ca),
+            (_, Some(mode)) => {
+                anyhow::bail!("swarmly.tls.client_auth={mode} requires swarmly.tls.client_ca")
+            }
+            _ => return Ok(None),
+        };
+
+        let mode = match mode {
+            Some("require") | None => ClientAuthMode::Require,
+            Some("optional") => ClientAuthMode::Optional,
+            Some(other) => anyhow::bail!(
+                "unknown swarmly.tls.client_auth value {other}, expected require or optional"
+            ),
+        };
+
+        Ok(Some(Self { mode, ca_path }))
+    }
+}
+
+impl UpstreamTls {
+    fn from_labels(labels: &HashMap<String, String>) -> anyhow::Result<Self> {
+        let get = |key: &str| labels.get(key).map(|v| v.trim()).filter(|v| !v.is_empty());
+
+        let skip_verify = match get("swarmly.tls.upstream_skip_verify") {
+            Some("true") => true,
+            Some("false") | None => false,
+            Some(other) => {
+                anyhow::bail!("invalid swarmly.tls.upstream_skip_verify value {other}")
+            }
+        };
+
+        let client_cert = match (
+            get("swarmly.tls.upstream_client_cert"),
+            get("swarmly.tls.upstream_client_key"),
+        ) {
+            (Some(cert), Some(key)) => Some((secret_path(cert), secret_path(key))),
+            (None, None) => None,
+            _ => anyhow::bail!(
+                "swarmly.tls.upstream_client_cert and swarmly.tls.upstream_client_key must be set together"
+            ),
+        };
+
+        Ok(Self {
+            ca_path: get("swarmly.tls.upstream_ca").map(secret_path),
+            skip_verify,
+            sni: get("swarmly.tls.upstream_sni").map(str::to_owned),
+            verify_hostname: get("swarmly.tls.upstream_verify_hostname").map(str::to_owned),
+            client_cert,
+        })
+    }
+}
+
+impl TlsProfile {
+    const INTERMEDIATE_CIPHERS: &str = "ECDHE-ECDSA-AES128-GCM-SHA256:ECDHE-RSA-AES128-GCM-SHA256:\
+        ECDHE-ECDSA-AES256-GCM-SHA384:ECDHE-RSA-AES256-GCM-SHA384:\
+        ECDHE-ECDSA-CHACHA20-POLY1305:ECDHE-RSA-CHACHA20-POLY1305:\
+        DHE-RSA-AES128-GCM-SHA256:DHE-RSA-AES256-GCM-SHA384:DHE-RSA-CHACHA20-POLY1305";
+
+    pub fn modern() -> Self {
+        Self {
+            min_version: TlsVersion::Tls13,
+            ciphers: None,
+            ciphersuites: None,
+        }
+    }
+
+    pub fn intermediate() -> Self {
+        Self {
+            min_version: TlsVersion::Tls12,
+            ciphers: Some(Self::INTERMEDIATE_CIPHERS.to_owned()),
+            ciphersuites: None,
+        }
+    }
+
+    /// Builds a profile from a base name and overrides, `None` when nothing is set.
+    pub fn parse(
+        profile: Option<&str>,
+        min_version: Option<&str>,
+        ciphers: Option<&str>,
+        ciphersuites: Option<&str>,
+    ) -> anyhow::Result<Option<Self>> {
+        let (profile, min_version, ciphers, ciphersuites) = (
+            non_empty(profile),
+            non_empty(min_version),
+            non_empty(ciphers),
+            non_empty(ciphersuites),
+        );
+
+        if profile.is_none() && min_version.is_none() && ciphers.is_none() && ciphersuites.is_none()
+        {
+            return Ok(None);
+        }
+
+        let mut result = match profile {
+            Some("modern") => Self::modern(),
+            Some("intermediate") | Some("custom") | None => Self::intermediate(),
+            Some(other) => anyhow::bail!(
+                "unknown tls profile {other}, expected modern, intermediate or custom"
+            ),
+        };
+
+        if profile == Some("custom")
+            && min_version.is_none()
+            && ciphers.is_none()
+            && ciphersuites.is_none()
+        {
+            anyhow::bail!("custom tls profile requires a min version, ciphers or ciphersuites");
+        }
+
+        if let Some(version) = min_version {
+            result.min_version = match version {
+                "1.2" => TlsVersion::Tls12,
+                "1.3" => TlsVersion::Tls13,
+                other => anyhow::bail!("unsupported tls min version {other}, expected 1.2 or 1.3"),
+            };
+        }
+
+        if let Some(ciphers) = ciphers {
+            result.ciphers = Some(ciphers.to_owned());
+        }
+
+        if let Some(ciphersuites) = ciphersuites {
+            result.ciphersuites = Some(ciphersuites.to_owned());
+        }
+
+        Ok(Some(result))
+    }
+}
+
+impl Hsts {
+    /// Global policy from `HSTS_MAX_AGE`, `HSTS_INCLUDE_SUBDOMAINS` and `HSTS_PRELOAD`.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let var = |key: &str| std::env::var(key).ok();
+
+        Self::parse(
+            var("HSTS_MAX_AGE").as_deref(),
+            var("HSTS_INCLUDE_SUBDOMAINS").as_deref(),
+            var("HSTS_PRELOAD").as_deref(),
+        )
+    }
+
+    pub fn parse(
+        max_age: Option<&str>,
+        include_subdomains: Option<&str>,
+        preload: Option<&str>,
+    ) -> anyhow::Result<Option<Self>> {
+        let max_age = match max_age.map(str::trim) {
+            Some(v) => v
+                .parse()
+                .with_context(|| format!("failed to parse hsts max age {v} as u64"))?,
+            None if include_subdomains.is_some() || preload.is_some() => {
+                anyhow::bail!("hsts options require a max age")
+            }
+            None => return Ok(None),
+        };
+
+        let flag = |name: &str, value: Option<&str>| match value.map(str::trim) {
+            Some("true") => Ok(true),
+            Some("false") | None => Ok(false),
+            Some(other) => {
+                anyhow::bail!("invalid hsts {name} value {other}, expected true or false")
+            }
+        };
+
+        Ok(Some(Self {
+            max_age,
+            include_subdomains: flag("include subdomains", include_subdomains)?,
+            preload: flag("preload", preload)?,
+        }))
+    }
+
+    pub fn header_value(&self) -> String {
+        let mut value = format!("max-age={}", self.max_age);
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if self.preload {
+            value.push_str("; preload");
+        }
+        value
+    }
+}
+
+fn non_empty(value: Option<&str>) -> Option<&str> {
+    value.map(str::trim).filter(|v| !v.is_empty())
+}
+
+/// Resolves a docker secret name to its mount path, absolute paths are kept as is.
+fn secret_path(value: &str) -> PathBuf {
+    if value.starts_with('/') {
+        PathBuf::from(value)
+    } else {
+        PathBuf::from(SECRETS_DIR).join(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hsts_flags_are_strict() {
+        let hsts = Hsts::parse(Some("31536000"), Some("true"), Some(" false ")).unwrap();
+        assert_eq!(
+            hsts.map(|h| h.header_value()).as_deref(),
+            Some("max-age=31536000; includeSubDomains")
+        );
+
+        assert!(Hsts::parse(Some("60"), Some("yes"), None).is_err());
+        assert!(Hsts::parse(Some("60"), None, Some("1")).is_err());
+        assert!(Hsts::parse(Some("60"), Some("TRUE"), None).is_err());
+    }
+}
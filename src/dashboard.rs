@@ -0,0 +1,172 @@
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use http::{Response, StatusCode};
+use pingora::apps::http_app::ServeHttp;
+use pingora::protocols::http::ServerSession;
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::config::provider::ConfigProvider;
+use crate::config::{ConfigStatus, RefreshResult};
+use crate::errors::{self, RecordedError};
+use crate::metrics;
+use crate::proxy::cidr::Cidr;
+use crate::proxy::{Gateway, RouteStatus};
+use crate::tls::{CertificateStatus, TlsResolver};
+
+static INDEX: &str = include_str!("dashboard/index.html");
+
+/// Read-only HTML overview of routes, backends, certificates and errors,
+/// served on `DASHBOARD_ADDR`.
+pub struct Dashboard<P> {
+    gateway: Gateway,
+    config: ConfigStatus,
+    tls: Option<TlsResolver<P>>,
+    credentials: Option<(String, String)>,
+    allow: Vec<Cidr>,
+}
+
+#[derive(Serialize)]
+struct Status {
+    now: DateTime<Utc>,
+    routes: Vec<RouteStatus>,
+    certificates: Vec<CertificateStatus>,
+    config: Option<RefreshResult>,
+    errors: Vec<RecordedError>,
+    /// Request counters per domain, the page turns them into rates.
+    requests: HashMap<String, u64>,
+}
+
+impl<P: ConfigProvider + Send + Sync + 'static> Dashboard<P> {
+    /// Fails unless `DASHBOARD_PASSWORD` or `DASHBOARD_ALLOW` is set, the
+    /// dashboard is never served unprotected.
+    pub fn new(
+        gateway: Gateway,
+        config: ConfigStatus,
+        tls: Option<TlsResolver<P>>,
+    ) -> anyhow::Result<Self> {
+        let password =
+            match std::env::var("DASHBOARD_PASSWORD") {
+                Ok(password) => Some(password),
+                Err(_) => match std::env::var("DASHBOARD_PASSWORD_FILE") {
+                    Ok(path) => Some(std::fs::read_to_string(&path).with_context(|| {
+                        format!("failed to read DASHBOARD_PASSWORD_FILE {path}")
+                    })?),
+                    Err(_) => None,
+                },
+            };
+
+        let credentials = password
+            .map(|p| p.trim().to_owned())
+            .filter(|p| !p.is_empty())
+            .map(|password| {
+                let username =
+                    std::env::var("DASHBOARD_USERNAME").unwrap_or_else(|_| "admin".to_owned());
+                (username, password)
+            });
+
+        let allow = match std::env::var("DASHBOARD_ALLOW") {
+            Ok(value) => Cidr::parse_list(&value).context("invalid DASHBOARD_ALLOW")?,
+            Err(_) => Vec::new(),
+        };
+
+        if credentials.is_none() && allow.is_empty() {
+            anyhow::bail!("DASHBOARD_ADDR requires DASHBOARD_PASSWORD or DASHBOARD_ALLOW");
+        }
+
+        Ok(Self {
+            gateway,
+            config,
+            tls,
+            credentials,
+            allow,
+        })
+    }
+
+    fn reject_unauthorized(&self, session: &ServerSession) -> Option<Response<Vec<u8>>> {
+        if !self.allow.is_empty() {
+            let client = session
+                .client_addr()
+                .and_then(|a| a.as_inet())
+                .map(|a| a.ip());
+
+            if !client.is_some_and(|ip| self.allow.iter().any(|cidr| cidr.contains(ip))) {
+                return Some(plain(StatusCode::FORBIDDEN, "forbidden"));
+            }
+        }
+
+        if let Some((username, password)) = &self.credentials {
+            let presented = session
+                .req_header()
+                .headers
+                .get("authorization")
+                .and_then(|h| h.to_str().ok())
+                .and_then(|h| h.strip_prefix("Basic "))
+                .and_then(|b| openssl::base64::decode_block(b.trim()).ok())
+                .unwrap_or_default();
+            let expected = format!("{username}:{password}").into_bytes();
+
+            let valid =
+                presented.len() == expected.len() && openssl::memcmp::eq(&presented, &expected);
+
+            if !valid {
+                let mut response = plain(StatusCode::UNAUTHORIZED, "unauthorized");
+                response.headers_mut().insert(
+                    "www-authenticate",
+                    http::HeaderValue::from_static("Basic realm=\"swarmly\""),
+                );
+                return Some(response);
+            }
+        }
+
+        None
+    }
+
+    async fn status(&self) -> Status {
+        let certificates = match &self.tls {
+            Some(tls) => tls.certificates().await,
+            None => Vec::new(),
+        };
+
+        Status {
+            now: Utc::now(),
+            routes: self.gateway.routes().await,
+            certificates,
+            config: self.config.get(),
+            errors: errors::recent(),
+            requests: metrics::requests_by_domain(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<P: ConfigProvider + Send + Sync + 'static> ServeHttp for Dashboard<P> {
+    async fn response(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        if let Some(response) = self.reject_unauthorized(session) {
+            return response;
+        }
+
+        match session.req_header().uri.path() {
+            "/" => respond(StatusCode::OK, "text/html; charset=utf-8", INDEX.into()),
+            "/api/status" => {
+                let body = serde_json::to_vec(&self.status().await).expect("status must serialize");
+                respond(StatusCode::OK, "application/json", body)
+            }
+            _ => plain(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+}
+
+fn respond(status: StatusCode, content_type: &str, body: Vec<u8>) -> Response<Vec<u8>> {
+    Response::builder()
+        .status(status)
+        .header("content-type", content_type)
+        .header("content-length", body.len())
+        .header("cache-control", "no-store")
+        .body(body)
+        .expect("response must be valid")
+}
+
+fn plain(status: StatusCode, message: &str) -> Response<Vec<u8>> {
+    respond(status, "text/plain", message.as_bytes().to_vec())
+}
@@ -0,0 +1,40 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{LazyLock, Mutex};
+
+// failures of background work, kept for the dashboard since they otherwise
+// only show up in the log
+
+const CAPACITY: usize = 50;
+
+static RECENT: LazyLock<Mutex<VecDeque<RecordedError>>> =
+    LazyLock::new(|| Mutex::new(VecDeque::with_capacity(CAPACITY)));
+
+#[derive(Clone, Serialize)]
+pub struct RecordedError {
+    pub at: DateTime<Utc>,
+    /// `config` or `acme`.
+    pub source: &'static str,
+    pub domain: Option<String>,
+    pub message: String,
+}
+
+pub fn record(source: &'static str, domain: Option<&str>, err: &anyhow::Error) {
+    let mut recent = RECENT.lock().unwrap();
+    if recent.len() == CAPACITY {
+        recent.pop_back();
+    }
+
+    recent.push_front(RecordedError {
+        at: Utc::now(),
+        source,
+        domain: domain.map(str::to_owned),
+        message: format!("{err:#}"),
+    });
+}
+
+/// Recorded errors, newest first.
+pub fn recent() -> Vec<RecordedError> {
+    RECENT.lock().unwrap().iter().cloned().collect()
+}
@@ -0,0 +1,234 @@
+use futures_util::StreamExt;
+use openssl::rand::rand_bytes;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::redis::RedisClient;
+
+const CHANNEL: &str = "swarmly:events";
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+type EventCallback =
+    dyn Fn(CertEvent) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync + 'static;
+
+/// Certificate change made by one node that the other nodes have to pick up.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CertEvent {
+    Updated(String),
+    Deleted(String),
+    Revoked(String),
+}
+
+/// Cluster-wide notifications over redis pub/sub.
+///
+/// Messages are plain text, `<node> <kind> <domain>`, so they can also be
+/// published by hand with `redis-cli PUBLISH swarmly:events`.
+#[derive(Clone)]
+pub struct EventBus {
+    redis: RedisClient,
+    node: String,
+    callbacks: Arc<RwLock<Vec<Box<EventCallback>>>>,
+}
+
+impl CertEvent {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Updated(_) => "cert.updated",
+            Self::Deleted(_) => "cert.deleted",
+            Self::Revoked(_) => "cert.revoked",
+        }
+    }
+
+    pub fn domain(&self) -> &str {
+        match self {
+            Self::Updated(domain) | Self::Deleted(domain) | Self::Revoked(domain) => domain,
+        }
+    }
+
+    /// `None` for unknown kinds and for domains that aren't a plain host name,
+    /// the domain ends up in cache file paths.
+    fn parse(kind: &str, domain: &str) -> Option<Self> {
+        if !is_valid_domain(domain) {
+            return None;
+        }
+
+        let domain = domain.to_owned();
+        match kind {
+            "cert.updated" => Some(Self::Updated(domain)),
+            "cert.deleted" => Some(Self::Deleted(domain)),
+            "cert.revoked" => Some(Self::Revoked(domain)),
+            _ => None,
+        }
+    }
+}
+
+/// Letters, digits, `-`, `*` and non-empty labels between dots.
+fn is_valid_domain(domain: &str) -> bool {
+    domain.len() <= 253
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'*')
+        })
+}
+
+impl EventBus {
+    pub fn new(redis: RedisClient) -> Self {
+        // a random id per process, so a node skips its own messages even if
+        // hostnames are shared
+        let mut id = [0u8; 8];
+        rand_bytes(&mut id).expect("failed to generate node id");
+
+        Self {
+            redis,
+            node: format!("{:016x}", u64::from_le_bytes(id)),
+            callbacks: Arc::new(RwLock::new(Vec::new())),
+        }
+    }
+
+    pub fn set_event_callback<F, Fut>(&self, callback: F)
+    where
+        F: Fn(CertEvent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let boxed = Box::new(move |event: CertEvent| {
+            Box::pin(callback(event)) as Pin<Box<dyn Future<Output = ()> + Send>>
+        });
+
+        self.callbacks.write().unwrap().push(boxed);
+    }
+
+    /// Publishes `event` to the other nodes, failures are only logged since
+    /// the change itself already happened.
+    pub async fn publish(&self, event: CertEvent) {
+        let message = format!("{} {} {}", self.node, event.kind(), event.domain());
+
+        if let Err(err) = self.redis.publish(CHANNEL, message.as_bytes()).await {
+            tracing::warn!(
+                "failed to publish {} for {}: {err:?}",
+                event.kind(),
+                event.domain()
+            );
+        }
+    }
+
+    async fn dispatch(&self, payload: &[u8]) {
+        let message = String::from_utf8_lossy(payload);
+        let mut parts = message.split_whitespace();
+
+        let (node, event) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(node), Some(kind), Some(domain)) => match CertEvent::parse(kind, domain) {
+                Some(event) => (node, event),
+                None => {
+                    tracing::warn!("ignoring unknown or invalid event: {message}");
+                    return;
+                }
+            },
+            _ => {
+                tracing::warn!("ignoring malformed event: {message}");
+                return;
+            }
+        };
+
+        if node == self.node {
+            return;
+        }
+
+        tracing::debug!(
+            "received {} for {} from {node}",
+            event.kind(),
+            event.domain()
+        );
+
+        let futures: Vec<_> = self
+            .callbacks
+            .read()
+            .unwrap()
+            .iter()
+            .map(|cb| cb(event.clone()))
+            .collect();
+
+        for fut in futures {
+            fut.await;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BackgroundService for EventBus {
+    async fn start(&self, shutdown: ShutdownWatch) {
+        loop {
+            if shutdown.borrow().has_changed() {
+                tracing::info!("stopping event listener..");
+                break;
+            }
+
+            let mut messages = match self.redis.subscribe(CHANNEL).await {
+                Ok(messages) => Box::pin(messages),
+                Err(err) => {
+                    tracing::debug!("failed to subscribe to {CHANNEL}: {err:?}");
+                    tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+                    continue;
+                }
+            };
+
+            tracing::info!("listening for events on {CHANNEL}");
+
+            while let Some(payload) = messages.next().await {
+                self.dispatch(&payload).await;
+            }
+
+            tracing::warn!("lost subscription to {CHANNEL}, resubscribing");
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_known_kinds() {
+        assert_eq!(
+            CertEvent::parse("cert.updated", "example.com"),
+            Some(CertEvent::Updated("example.com".to_owned()))
+        );
+        assert_eq!(
+            CertEvent::parse("cert.deleted", "*.example.com"),
+            Some(CertEvent::Deleted("*.example.com".to_owned()))
+        );
+        assert_eq!(
+            CertEvent::parse("cert.revoked", "a-b.example.com"),
+            Some(CertEvent::Revoked("a-b.example.com".to_owned()))
+        );
+        assert_eq!(CertEvent::parse("cert.renamed", "example.com"), None);
+    }
+
+    #[test]
+    fn rejects_domains_outside_the_cache_dir() {
+        for domain in [
+            "",
+            "..",
+            "../../etc/passwd",
+            "example.com/../x",
+            "example..com",
+            ".example.com",
+            "example.com.",
+            "example\\com",
+            "exa\0mple.com",
+            "exämple.com",
+        ] {
+            assert_eq!(CertEvent::parse("cert.deleted", domain), None, "{domain:?}");
+        }
+
+        let long = format!("{}.com", "a.".repeat(125));
+        assert_eq!(long.len(), 254);
+        assert_eq!(CertEvent::parse("cert.deleted", &long), None);
+    }
+}
@@ -1,57 +1,92 @@
+use clap::Parser;
 use pingora::prelude::*;
+use pingora::proxy::http_proxy;
 use pingora::services::listening::Service;
+use std::process::ExitCode;
 use tracing::Level;
 use tracing_subscriber::FmtSubscriber;
 
+use self::admin::AdminService;
+use self::cli::{Cli, Command};
 use self::config::ConfigRefresher;
-use self::config::provider::docker::DockerConfig;
+use self::config::provider::Hsts;
+use self::config::provider::{CompositeConfig, GuardedConfig};
+use self::dashboard::Dashboard;
+use self::events::EventBus;
+use self::proxy::CountConnections;
 use self::proxy::Gateway;
 use self::proxy::SwarmProxy;
+use self::proxy::access_log::AccessLog;
+use self::proxy::request_id::RequestIds;
+use self::redis::{FailoverWatcher, RedisMonitor};
 use self::tls::AcmeChallengeService;
 use self::tls::TlsResolver;
 
+mod admin;
+mod cli;
 mod config;
+mod dashboard;
+mod errors;
+mod events;
+mod metrics;
 mod proxy;
 mod redis;
+mod telemetry;
 mod tls;
 
-fn main() {
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match cli.command {
+        None | Some(Command::Serve) => serve(),
+        Some(command) => cli::run(command),
+    }
+}
+
+fn serve() -> ! {
     let subscriber = FmtSubscriber::builder()
         .with_max_level(Level::INFO)
         .finish();
 
     tracing::subscriber::set_global_default(subscriber).unwrap();
 
+    telemetry::init().expect("failed to set up otlp export");
+
     let mut server = Server::new(None).unwrap();
 
     let gateway = Gateway::default();
-    let config_provider = DockerConfig::new().unwrap();
-
-    let (redis, tls_resolver) = tokio::runtime::Builder::new_current_thread()
-        .enable_all()
-        .build()
-        .expect("failed to build tokio runtime for init")
-        .block_on(async {
-            let redis = redis::RedisClient::from_env()
-                .await
-                .expect("failed to connect to redis");
-
-            if redis.is_some() {
-                tracing::info!("redis connected — using distributed mode");
-            } else {
-                tracing::info!("no REDIS_URL set — using local filesystem storage");
-            }
-
-            let acme_challenge_inner = AcmeChallengeService::new(redis.clone());
-            let tls_resolver =
-                TlsResolver::new(config_provider.clone(), acme_challenge_inner, redis.clone())
+    let config_provider = config::provider::from_env().expect("invalid config provider settings");
+
+    let (redis, events, acme_challenge, tls_resolver) =
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("failed to build tokio runtime for init")
+            .block_on(async {
+                let redis = redis::RedisClient::from_env()
                     .await
-                    .expect("failed to create tls resolver");
+                    .expect("failed to connect to redis");
+
+                if redis.is_some() {
+                    tracing::info!("redis connected — using distributed mode");
+                } else {
+                    tracing::info!("no REDIS_URL set — using local filesystem storage");
+                }
+
+                let events = redis.clone().map(EventBus::new);
+
+                let acme_challenge = AcmeChallengeService::new(redis.clone());
+                let tls_resolver = TlsResolver::new(
+                    config_provider.clone(),
+                    acme_challenge.clone(),
+                    redis.clone().zip(events.clone()),
+                )
+                .await
+                .expect("failed to create tls resolver");
 
-            (redis, tls_resolver)
-        });
+                (redis, events, acme_challenge, tls_resolver)
+            });
 
-    let acme_challenge = AcmeChallengeService::new(redis.clone());
     let mut acme_challenge_service =
         Service::new("acme challenge service".to_string(), acme_challenge);
 
@@ -60,21 +95,73 @@ fn main() {
     server.add_service(acme_challenge_service);
 
     let tls_enabled = std::env::var("ACME_EMAIL").is_ok();
-    let proxy = SwarmProxy::new(gateway.clone(), tls_enabled);
-    let mut proxy_service = http_proxy_service(&server.configuration, proxy);
+    let hsts = Hsts::from_env().expect("invalid hsts settings");
+    let access_log = AccessLog::from_env().expect("invalid access log settings");
+    let request_ids = RequestIds::from_env().expect("invalid TRUSTED_PROXIES");
+    let proxy = SwarmProxy::new(
+        gateway.clone(),
+        tls_enabled,
+        hsts,
+        redis.clone(),
+        access_log,
+        request_ids,
+    );
+    let mut proxy_service = Service::new(
+        "proxy service".to_string(),
+        CountConnections::new(http_proxy(&server.configuration, proxy)),
+    );
 
     proxy_service.add_tcp("0.0.0.0:80");
 
-    if let Some(tls_resolver) = tls_resolver {
+    if let Some(tls_resolver) = &tls_resolver {
         proxy_service.add_tls_with_settings("0.0.0.0:443", None, tls_resolver.as_tls_settings());
     }
 
     server.add_service(proxy_service);
 
-    let config_refresher = ConfigRefresher::new(config_provider, gateway);
+    if let Ok(addr) = std::env::var("METRICS_ADDR") {
+        let mut metrics_service = Service::prometheus_http_service();
+        metrics_service.add_tcp(addr.trim());
+        server.add_service(metrics_service);
+    }
+
+    let config_refresher = ConfigRefresher::new(config_provider, gateway.clone());
+
+    if let Ok(addr) = std::env::var("DASHBOARD_ADDR") {
+        let dashboard = Dashboard::new(
+            gateway.clone(),
+            config_refresher.status(),
+            tls_resolver.clone(),
+        )
+        .expect("invalid dashboard settings");
+        let mut dashboard_service = Service::new("dashboard".to_string(), dashboard);
+        dashboard_service.add_tcp(addr.trim());
+        server.add_service(dashboard_service);
+    }
+
+    let admin = AdminService::new(gateway, config_refresher.status(), tls_resolver)
+        .expect("invalid admin api settings");
+    let mut admin_service = Service::new("admin api".to_string(), admin);
+    admin_service.add_tcp(&AdminService::<GuardedConfig<CompositeConfig>>::addr());
+    server.add_service(admin_service);
+
     let config_service = background_service("config refresher", config_refresher);
 
     server.add_service(config_service);
 
+    if let Some(redis) = redis {
+        if redis.is_sentinel() {
+            let watcher = FailoverWatcher::new(redis.clone());
+            server.add_service(background_service("redis failover watcher", watcher));
+        }
+
+        let redis_monitor = background_service("redis monitor", RedisMonitor::new(redis));
+        server.add_service(redis_monitor);
+    }
+
+    if let Some(events) = events {
+        server.add_service(background_service("event listener", events));
+    }
+
     server.run_forever()
 }
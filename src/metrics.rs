@@ -0,0 +1,148 @@
+use prometheus::core::Collector;
+use prometheus::{
+    Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, register_histogram,
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec,
+};
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+// all metrics go to the default registry served by pingora's prometheus service
+
+/// Requests by routed domain and status class (`2xx`, `4xx`, ...).
+pub static REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "swarmly_requests_total",
+        "Requests by domain and status class",
+        &["domain", "status"]
+    )
+    .unwrap()
+});
+
+pub static REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "swarmly_request_duration_seconds",
+        "Total request latency by domain",
+        &["domain"]
+    )
+    .unwrap()
+});
+
+pub static UPSTREAM_CONNECT_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "swarmly_upstream_connect_errors_total",
+        "Failed connections to upstreams by domain",
+        &["domain"]
+    )
+    .unwrap()
+});
+
+/// Requests between their first byte and the access log line. Counts
+/// requests, not connections, so idle keep-alive connections aren't included.
+pub static IN_FLIGHT_REQUESTS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "swarmly_requests_in_flight",
+        "Requests currently being processed"
+    )
+    .unwrap()
+});
+
+/// Open downstream connections, idle keep-alive connections included.
+pub static ACTIVE_CONNECTIONS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("swarmly_connections_active", "Open downstream connections").unwrap()
+});
+
+/// `1` when the upstream answered the last health check, `0` otherwise.
+pub static BACKEND_UP: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "swarmly_backend_up",
+        "Upstream health by domain and backend address",
+        &["domain", "backend"]
+    )
+    .unwrap()
+});
+
+pub static CERT_EXPIRY: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "swarmly_cert_expiry_timestamp_seconds",
+        "Expiry of the served certificate as a unix timestamp",
+        &["domain"]
+    )
+    .unwrap()
+});
+
+pub static ACME_ISSUANCE: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "swarmly_acme_issuance_total",
+        "Certificate issuance attempts by acme provider and result",
+        &["provider", "result"]
+    )
+    .unwrap()
+});
+
+/// `1` for every domain routed by more than one service or provider.
+pub static ROUTE_CONFLICTS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "swarmly_route_conflicts",
+        "Contested domains by scope and resolution",
+        &["scope", "domain", "resolution"]
+    )
+    .unwrap()
+});
+
+pub static CONFIG_REFRESH_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "swarmly_config_refresh_duration_seconds",
+        "Duration of config provider refreshes"
+    )
+    .unwrap()
+});
+
+pub static CONFIG_REFRESH_ERRORS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "swarmly_config_refresh_errors_total",
+        "Failed config provider refreshes"
+    )
+    .unwrap()
+});
+
+/// `1` while a config update dropping too many domains is held back.
+pub static CONFIG_UPDATE_HELD: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "swarmly_config_update_held",
+        "Config update held back for dropping too many domains"
+    )
+    .unwrap()
+});
+
+pub fn status_class(status: u16) -> &'static str {
+    match status {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        500..=599 => "5xx",
+        _ => "other",
+    }
+}
+
+/// Total requests per domain over all status classes.
+pub fn requests_by_domain() -> HashMap<String, u64> {
+    let mut totals = HashMap::new();
+
+    for family in REQUESTS.collect() {
+        for metric in family.get_metric() {
+            let domain = metric
+                .get_label()
+                .iter()
+                .find(|label| label.get_name() == "domain")
+                .map(|label| label.get_value().to_owned());
+
+            if let Some(domain) = domain {
+                *totals.entry(domain).or_default() += metric.get_counter().get_value() as u64;
+            }
+        }
+    }
+
+    totals
+}
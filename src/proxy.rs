@@ -1,35 +1,95 @@
 use std::str::FromStr;
-use std::time::Instant;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use bytes::Bytes;
+use opentelemetry::KeyValue;
 use pingora::Result;
-use pingora::http::ResponseHeader;
+use pingora::http::{RequestHeader, ResponseHeader};
 use pingora::prelude::*;
+use pingora::protocols::Digest;
+use pingora::protocols::http::ServerSession;
 use pingora::protocols::l4::socket::SocketAddr;
 use pingora::proxy::{ProxyHttp, Session};
 
-pub use self::gateway::Gateway;
+use self::access_log::{AccessLog, Entry};
+pub use self::connections::CountConnections;
+pub use self::gateway::{Gateway, RouteStatus};
+use self::request_id::{REQUEST_ID_HEADER, RequestIds, TRACEPARENT_HEADER, TraceContext};
+pub use self::route::Route;
+use self::spans::Timings;
+use crate::config::provider::{ClientAuthMode, Hsts};
+use crate::metrics;
+use crate::redis::RedisClient;
+use crate::telemetry;
+use crate::tls::{ClientIdentity, HandshakeInfo};
 
+pub mod access_log;
+pub mod cidr;
+mod connections;
 mod discovery;
 mod gateway;
+pub mod request_id;
+mod route;
+mod spans;
+
+const CLIENT_CERT_SUBJECT_HEADER: &str = "x-client-cert-subject";
+const CLIENT_CERT_SAN_HEADER: &str = "x-client-cert-san";
 
 pub struct ProxyCtx {
+    domain: String,
+    route: Option<Arc<Route>>,
     upstream: Option<SocketAddr>,
-    upstream_tls: bool,
-    upstream_sni: String,
     start: Instant,
+    upstream_start: Option<Instant>,
+    upstream_latency: Option<Duration>,
+    request_id: String,
+    trace: Option<TraceContext>,
+    timings: Timings,
+}
+
+impl ProxyCtx {
+    /// Domain label for metrics, unrouted hosts are grouped to keep the
+    /// label set bounded.
+    fn metrics_domain(&self) -> &str {
+        match self.route {
+            Some(_) => &self.domain,
+            None => "unrouted",
+        }
+    }
+}
+
+impl Drop for ProxyCtx {
+    fn drop(&mut self) {
+        metrics::IN_FLIGHT_REQUESTS.dec();
+    }
 }
 
 pub struct SwarmProxy {
     gateway: Gateway,
     tls_enabled: bool,
+    hsts: Option<Hsts>,
+    redis: Option<RedisClient>,
+    access_log: AccessLog,
+    request_ids: RequestIds,
 }
 
 impl SwarmProxy {
-    pub fn new(gateway: Gateway, tls_enabled: bool) -> Self {
+    pub fn new(
+        gateway: Gateway,
+        tls_enabled: bool,
+        hsts: Option<Hsts>,
+        redis: Option<RedisClient>,
+        access_log: AccessLog,
+        request_ids: RequestIds,
+    ) -> Self {
         Self {
             gateway,
             tls_enabled,
+            hsts,
+            redis,
+            access_log,
+            request_ids,
         }
     }
 }
@@ -39,11 +99,21 @@ impl ProxyHttp for SwarmProxy {
     type CTX = ProxyCtx;
 
     fn new_ctx(&self) -> Self::CTX {
+        metrics::IN_FLIGHT_REQUESTS.inc();
+
         ProxyCtx {
+            domain: String::new(),
+            route: None,
             upstream: None,
-            upstream_tls: false,
-            upstream_sni: String::new(),
             start: Instant::now(),
+            upstream_start: None,
+            upstream_latency: None,
+            request_id: String::new(),
+            trace: None,
+            timings: Timings {
+                start: Some(SystemTime::now()),
+                ..Default::default()
+            },
         }
     }
 
@@ -51,6 +121,12 @@ impl ProxyHttp for SwarmProxy {
     where
         Self::CTX: Send + Sync,
     {
+        let incoming = |name: &str| session.get_header(name).and_then(|h| h.to_str().ok());
+        ctx.request_id = self
+            .request_ids
+            .request_id(client_ip(session), incoming(REQUEST_ID_HEADER));
+        ctx.trace = self.request_ids.trace(incoming(TRACEPARENT_HEADER));
+
         let path = session.req_header().uri.path();
 
         if path.starts_with("/.well-known/acme-challenge/") {
@@ -61,14 +137,21 @@ impl ProxyHttp for SwarmProxy {
         }
 
         if path == "/health" || path == "/healthz" {
+            // still 200 while degraded, cached certificates keep being served
+            let body: &'static [u8] = match &self.redis {
+                Some(redis) if !redis.is_healthy() => b"degraded: redis unreachable",
+                _ => b"ok",
+            };
+
             let mut header = ResponseHeader::build(200, None)?;
             header.insert_header("content-type", "text/plain")?;
-            header.insert_header("content-length", "2")?;
+            header.insert_header(REQUEST_ID_HEADER, &ctx.request_id)?;
+            header.insert_header("content-length", body.len().to_string())?;
             session
                 .write_response_header(Box::new(header), false)
                 .await?;
             session
-                .write_response_body(Some(Bytes::from_static(b"ok")), true)
+                .write_response_body(Some(Bytes::from_static(body)), true)
                 .await?;
             return Ok(true);
         }
@@ -93,6 +176,7 @@ impl ProxyHttp for SwarmProxy {
                 );
                 let mut header = ResponseHeader::build(301, None)?;
                 header.insert_header("location", location)?;
+                header.insert_header(REQUEST_ID_HEADER, &ctx.request_id)?;
                 header.insert_header("content-length", "0")?;
                 session
                     .write_response_header(Box::new(header), true)
@@ -109,26 +193,31 @@ impl ProxyHttp for SwarmProxy {
         let domain = match domain {
             Some(host) => host.trim(),
             None => {
-                session.respond_error(400).await?;
+                respond_error(session, ctx, 400).await?;
                 return Ok(true);
             }
         };
 
-        let (backend, tls) = match self.gateway.process(domain).await {
+        let (backend, route) = match self.gateway.process(domain).await {
             Some(result) => result,
             None => {
-                session.respond_error(404).await?;
+                respond_error(session, ctx, 404).await?;
                 return Ok(true);
             }
         };
 
+        let client_auth = route.options.client_auth.as_ref().map(|c| c.mode);
+        if client_auth == Some(ClientAuthMode::Require)
+            && client_identity(session, domain).is_none()
+        {
+            respond_error(session, ctx, 403).await?;
+            return Ok(true);
+        }
+
+        ctx.domain = domain.to_owned();
+        ctx.route = Some(route);
         ctx.upstream = Some(backend.addr);
-        ctx.upstream_tls = tls;
-        ctx.upstream_sni = if tls {
-            domain.to_owned()
-        } else {
-            String::new()
-        };
+        ctx.timings.routed = Some(SystemTime::now());
 
         Ok(false)
     }
@@ -139,11 +228,153 @@ impl ProxyHttp for SwarmProxy {
         ctx: &mut Self::CTX,
     ) -> Result<Box<HttpPeer>> {
         let upstream = ctx.upstream.as_ref().expect("upstream must be selected");
-        Ok(Box::new(HttpPeer::new(
-            upstream,
-            ctx.upstream_tls,
-            ctx.upstream_sni.clone(),
-        )))
+        ctx.upstream_start = Some(Instant::now());
+        ctx.timings.upstream_start = Some(SystemTime::now());
+
+        let route = match &ctx.route {
+            Some(route) if route.tls => route,
+            _ => return Ok(Box::new(HttpPeer::new(upstream, false, String::new()))),
+        };
+
+        if route.upstream_tls_failed {
+            return Error::e_explain(
+                HTTPStatus(502),
+                "upstream tls certificates of the route failed to load",
+            );
+        }
+
+        let upstream_tls = &route.options.upstream_tls;
+        let sni = upstream_tls
+            .sni
+            .clone()
+            .unwrap_or_else(|| ctx.domain.clone());
+
+        let mut peer = HttpPeer::new(upstream, true, sni);
+        peer.options.verify_cert = !upstream_tls.skip_verify;
+        peer.options.verify_hostname = !upstream_tls.skip_verify;
+        peer.options.alternative_cn = upstream_tls.verify_hostname.clone();
+        peer.options.ca = route.upstream_ca.clone();
+        peer.client_cert_key = route.upstream_cert.clone();
+
+        Ok(Box::new(peer))
+    }
+
+    async fn connected_to_upstream(
+        &self,
+        _session: &mut Session,
+        reused: bool,
+        _peer: &HttpPeer,
+        #[cfg(unix)] _fd: std::os::unix::io::RawFd,
+        #[cfg(windows)] _sock: std::os::windows::io::RawSocket,
+        digest: Option<&Digest>,
+        ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        ctx.timings.connected(reused, digest);
+        Ok(())
+    }
+
+    fn fail_to_connect(
+        &self,
+        _session: &mut Session,
+        _peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        e: Box<pingora::Error>,
+    ) -> Box<pingora::Error> {
+        metrics::UPSTREAM_CONNECT_ERRORS
+            .with_label_values(&[ctx.metrics_domain()])
+            .inc();
+        e
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        upstream_request.insert_header(REQUEST_ID_HEADER, &ctx.request_id)?;
+        // without TRACEPARENT an incoming header is passed through untouched
+        if let Some(trace) = &ctx.trace {
+            upstream_request.insert_header(TRACEPARENT_HEADER, trace.header_value())?;
+        }
+
+        upstream_request.remove_header(CLIENT_CERT_SUBJECT_HEADER);
+        upstream_request.remove_header(CLIENT_CERT_SAN_HEADER);
+
+        if ctx
+            .route
+            .as_ref()
+            .is_none_or(|r| r.options.client_auth.is_none())
+        {
+            return Ok(());
+        }
+
+        if let Some(identity) = client_identity(session, &ctx.domain) {
+            upstream_request
+                .insert_header(CLIENT_CERT_SUBJECT_HEADER, header_safe(&identity.subject))?;
+            if !identity.sans.is_empty() {
+                upstream_request.insert_header(
+                    CLIENT_CERT_SAN_HEADER,
+                    header_safe(&identity.sans.join(", ")),
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn upstream_response_filter(
+        &self,
+        _session: &mut Session,
+        _upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        ctx.upstream_latency = ctx.upstream_start.map(|start| start.elapsed());
+        ctx.timings.first_byte = Some(SystemTime::now());
+        Ok(())
+    }
+
+    async fn response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        upstream_response.insert_header(REQUEST_ID_HEADER, &ctx.request_id)?;
+
+        let is_tls = session.digest().is_some_and(|d| d.ssl_digest.is_some());
+
+        if !is_tls
+            || upstream_response
+                .headers
+                .contains_key("strict-transport-security")
+        {
+            return Ok(());
+        }
+
+        let hsts = ctx
+            .route
+            .as_ref()
+            .and_then(|r| r.options.hsts.as_ref())
+            .or(self.hsts.as_ref());
+
+        if let Some(hsts) = hsts.filter(|h| h.max_age > 0) {
+            upstream_response.insert_header("strict-transport-security", hsts.header_value())?;
+        }
+
+        Ok(())
     }
 
     async fn logging(
@@ -152,33 +383,130 @@ impl ProxyHttp for SwarmProxy {
         _e: Option<&pingora::Error>,
         ctx: &mut Self::CTX,
     ) {
-        let req = session.req_header();
-        let method = req.method.as_str();
-        let host = req
-            .headers
-            .get("host")
-            .and_then(|h| h.to_str().ok())
-            .unwrap_or("-");
-        let path = req.uri.path();
         let status = session
             .response_written()
             .map(|r| r.status.as_u16())
             .unwrap_or(0);
-        let latency_ms = ctx.start.elapsed().as_millis();
-        let client = session
-            .client_addr()
-            .and_then(|a| a.as_inet())
-            .map(|a| a.ip().to_string())
-            .unwrap_or_else(|| "-".to_owned());
-
-        tracing::info!(
-            "{} \"{} {} {}\" {} {}ms",
-            client,
-            method,
-            host,
-            path,
+        let elapsed = ctx.start.elapsed();
+
+        let domain = ctx.metrics_domain();
+        metrics::REQUESTS
+            .with_label_values(&[domain, metrics::status_class(status)])
+            .inc();
+        metrics::REQUEST_DURATION
+            .with_label_values(&[domain])
+            .observe(elapsed.as_secs_f64());
+
+        let req = session.req_header();
+        let header = |name: &str| req.headers.get(name).and_then(|h| h.to_str().ok());
+        let ssl_digest = session.digest().and_then(|d| d.ssl_digest.as_ref());
+
+        let entry = Entry {
+            time: chrono::Utc::now(),
+            client: client_ip(session).map(|ip| ip.to_string()),
+            method: req.method.as_str(),
+            host: header("host"),
+            path: req.uri.path(),
+            query: req.uri.query(),
+            protocol: protocol(req.version),
             status,
-            latency_ms
-        );
+            bytes_in: session.body_bytes_read(),
+            bytes_out: session.body_bytes_sent(),
+            upstream: ctx
+                .upstream_start
+                .and(ctx.upstream.as_ref())
+                .map(|addr| addr.to_string()),
+            upstream_ms: ctx.upstream_latency.map(|d| d.as_millis() as u64),
+            duration_ms: elapsed.as_millis() as u64,
+            tls_version: ssl_digest.map(|d| d.version.as_ref()),
+            sni: ssl_digest
+                .and_then(|d| d.extension.get::<HandshakeInfo>())
+                .and_then(|info| info.sni.as_deref()),
+            user_agent: header("user-agent"),
+            referer: header("referer"),
+            request_id: Some(&ctx.request_id)
+                .filter(|id| !id.is_empty())
+                .map(String::as_str),
+            trace_id: ctx.trace.as_ref().map(|t| t.trace_id.as_str()),
+        };
+
+        self.access_log.write(&entry);
+
+        if telemetry::is_enabled() {
+            let mut attributes = vec![
+                KeyValue::new("http.request.method", entry.method.to_owned()),
+                KeyValue::new("url.path", entry.path.to_owned()),
+                KeyValue::new("http.response.status_code", i64::from(status)),
+                KeyValue::new("swarmly.request_id", ctx.request_id.clone()),
+            ];
+            if let Some(host) = entry.host {
+                attributes.push(KeyValue::new("server.address", host.to_owned()));
+            }
+            if let Some(client) = entry.client.clone() {
+                attributes.push(KeyValue::new("client.address", client));
+            }
+            if let Some(upstream) = entry.upstream.clone() {
+                attributes.push(KeyValue::new("swarmly.upstream", upstream));
+            }
+
+            spans::export(
+                session,
+                &ctx.timings,
+                ctx.trace.as_ref(),
+                status,
+                attributes,
+            );
+        }
     }
 }
+
+/// Answers with an error status before the request is proxied. Such
+/// responses skip `response_filter`, so the request id and trace context
+/// are set here.
+async fn respond_error(session: &mut Session, ctx: &ProxyCtx, code: u16) -> Result<()> {
+    let mut header = ServerSession::generate_error(code);
+    header.insert_header(REQUEST_ID_HEADER, &ctx.request_id)?;
+    if let Some(trace) = &ctx.trace {
+        header.insert_header(TRACEPARENT_HEADER, trace.header_value())?;
+    }
+
+    session.write_error_response(header, Bytes::new()).await
+}
+
+fn client_ip(session: &Session) -> Option<std::net::IpAddr> {
+    session
+        .client_addr()
+        .and_then(|a| a.as_inet())
+        .map(|a| a.ip())
+}
+
+/// Verified client certificate of the connection, if it was presented for `domain`.
+fn client_identity<'a>(session: &'a Session, domain: &str) -> Option<&'a ClientIdentity> {
+    session
+        .digest()?
+        .ssl_digest
+        .as_ref()?
+        .extension
+        .get::<HandshakeInfo>()
+        .filter(|info| info.sni.as_deref() == Some(domain))?
+        .client
+        .as_ref()
+}
+
+fn protocol(version: http::Version) -> &'static str {
+    match version {
+        http::Version::HTTP_09 => "HTTP/0.9",
+        http::Version::HTTP_10 => "HTTP/1.0",
+        http::Version::HTTP_11 => "HTTP/1.1",
+        http::Version::HTTP_2 => "HTTP/2.0",
+        http::Version::HTTP_3 => "HTTP/3.0",
+        _ => "-",
+    }
+}
+
+fn header_safe(value: &str) -> String {
+    value
+        .chars()
+        .filter(|c| c.is_ascii_graphic() || *c == ' ')
+        .collect()
+}
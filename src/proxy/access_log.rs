@@ -0,0 +1,272 @@
+use anyhow::Context;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Serialize, Serializer};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::Write;
+use std::sync::Mutex;
+
+/// Access log format and destination, from `ACCESS_LOG_FORMAT` and
+/// `ACCESS_LOG_OUTPUT`.
+pub struct AccessLog {
+    format: Format,
+    output: Output,
+}
+
+enum Format {
+    /// `{client} "{method} {host} {path}" {status} {duration_ms}ms`
+    Default,
+    /// Apache/nginx combined log format.
+    Combined,
+    Json,
+    Custom(Vec<Segment>),
+}
+
+enum Segment {
+    Literal(String),
+    Field(Field),
+}
+
+#[derive(Clone, Copy)]
+enum Field {
+    Time,
+    Client,
+    Method,
+    Host,
+    Path,
+    Query,
+    Protocol,
+    Status,
+    BytesIn,
+    BytesOut,
+    Upstream,
+    UpstreamMs,
+    DurationMs,
+    TlsVersion,
+    Sni,
+    UserAgent,
+    Referer,
+    RequestId,
+    TraceId,
+}
+
+enum Output {
+    /// Through the regular log, next to everything else swarmly logs.
+    Log,
+    Stdout,
+    Stderr,
+    File(Mutex<File>),
+}
+
+/// A single finished request.
+#[derive(Serialize)]
+pub struct Entry<'a> {
+    #[serde(serialize_with = "serialize_time")]
+    pub time: DateTime<Utc>,
+    pub client: Option<String>,
+    pub method: &'a str,
+    pub host: Option<&'a str>,
+    pub path: &'a str,
+    pub query: Option<&'a str>,
+    pub protocol: &'static str,
+    pub status: u16,
+    pub bytes_in: usize,
+    pub bytes_out: usize,
+    pub upstream: Option<String>,
+    /// Time from picking the upstream until its response headers arrived.
+    pub upstream_ms: Option<u64>,
+    pub duration_ms: u64,
+    pub tls_version: Option<&'a str>,
+    pub sni: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+    pub referer: Option<&'a str>,
+    pub request_id: Option<&'a str>,
+    pub trace_id: Option<&'a str>,
+}
+
+impl AccessLog {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let format = match std::env::var("ACCESS_LOG_FORMAT").ok().as_deref() {
+            None | Some("") | Some("default") => Format::Default,
+            Some("combined") => Format::Combined,
+            Some("json") => Format::Json,
+            Some(value) => match value.strip_prefix("custom:") {
+                Some(template) => Format::Custom(parse_template(template)?),
+                None => anyhow::bail!(
+                    "invalid ACCESS_LOG_FORMAT {value}, expected default, combined, json or custom:<template>"
+                ),
+            },
+        };
+
+        let output = match std::env::var("ACCESS_LOG_OUTPUT").ok().as_deref() {
+            None | Some("") | Some("log") => Output::Log,
+            Some("stdout") => Output::Stdout,
+            Some("stderr") => Output::Stderr,
+            Some(path) => {
+                let file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("failed to open access log {path}"))?;
+                Output::File(Mutex::new(file))
+            }
+        };
+
+        Ok(Self { format, output })
+    }
+
+    pub fn write(&self, entry: &Entry) {
+        let line = match &self.format {
+            Format::Default => format!(
+                "{} \"{} {} {}\" {} {}ms",
+                entry.field(Field::Client),
+                entry.method,
+                entry.field(Field::Host),
+                entry.path,
+                entry.status,
+                entry.duration_ms
+            ),
+            Format::Combined => format!(
+                "{} - - [{}] \"{} {}{}{} {}\" {} {} \"{}\" \"{}\"",
+                entry.field(Field::Client),
+                entry.time.format("%d/%b/%Y:%H:%M:%S %z"),
+                entry.method,
+                entry.path,
+                if entry.query.is_some() { "?" } else { "" },
+                entry.query.unwrap_or_default(),
+                entry.protocol,
+                entry.status,
+                entry.bytes_out,
+                entry.field(Field::Referer),
+                entry.field(Field::UserAgent)
+            ),
+            Format::Json => match serde_json::to_string(entry) {
+                Ok(line) => line,
+                Err(err) => {
+                    tracing::error!("failed to serialize access log entry: {err}");
+                    return;
+                }
+            },
+            Format::Custom(segments) => segments
+                .iter()
+                .map(|segment| match segment {
+                    Segment::Literal(text) => Cow::Borrowed(text.as_str()),
+                    Segment::Field(field) => entry.field(*field),
+                })
+                .collect(),
+        };
+
+        // lines are short and written in one call, so blocking on the file
+        // is cheap compared to the request itself
+        let result = match &self.output {
+            Output::Log => {
+                tracing::info!("{line}");
+                Ok(())
+            }
+            Output::Stdout => writeln!(std::io::stdout().lock(), "{line}"),
+            Output::Stderr => writeln!(std::io::stderr().lock(), "{line}"),
+            Output::File(file) => writeln!(file.lock().unwrap(), "{line}"),
+        };
+
+        if let Err(err) = result {
+            tracing::warn!("failed to write access log: {err}");
+        }
+    }
+}
+
+impl Entry<'_> {
+    fn field(&self, field: Field) -> Cow<'_, str> {
+        match field {
+            Field::Time => Cow::Owned(self.time.to_rfc3339_opts(SecondsFormat::Millis, true)),
+            Field::Client => or_dash(self.client.as_deref()),
+            Field::Method => Cow::Borrowed(self.method),
+            Field::Host => or_dash(self.host),
+            Field::Path => Cow::Borrowed(self.path),
+            Field::Query => or_dash(self.query),
+            Field::Protocol => Cow::Borrowed(self.protocol),
+            Field::Status => Cow::Owned(self.status.to_string()),
+            Field::BytesIn => Cow::Owned(self.bytes_in.to_string()),
+            Field::BytesOut => Cow::Owned(self.bytes_out.to_string()),
+            Field::Upstream => or_dash(self.upstream.as_deref()),
+            Field::UpstreamMs => match self.upstream_ms {
+                Some(ms) => Cow::Owned(ms.to_string()),
+                None => Cow::Borrowed("-"),
+            },
+            Field::DurationMs => Cow::Owned(self.duration_ms.to_string()),
+            Field::TlsVersion => or_dash(self.tls_version),
+            Field::Sni => or_dash(self.sni),
+            Field::UserAgent => or_dash(self.user_agent),
+            Field::Referer => or_dash(self.referer),
+            Field::RequestId => or_dash(self.request_id),
+            Field::TraceId => or_dash(self.trace_id),
+        }
+    }
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Self> {
+        let field = match name {
+            "time" => Self::Time,
+            "client" => Self::Client,
+            "method" => Self::Method,
+            "host" => Self::Host,
+            "path" => Self::Path,
+            "query" => Self::Query,
+            "protocol" => Self::Protocol,
+            "status" => Self::Status,
+            "bytes_in" => Self::BytesIn,
+            "bytes_out" => Self::BytesOut,
+            "upstream" => Self::Upstream,
+            "upstream_ms" => Self::UpstreamMs,
+            "duration_ms" => Self::DurationMs,
+            "tls_version" => Self::TlsVersion,
+            "sni" => Self::Sni,
+            "user_agent" => Self::UserAgent,
+            "referer" => Self::Referer,
+            "request_id" => Self::RequestId,
+            "trace_id" => Self::TraceId,
+            _ => return None,
+        };
+
+        Some(field)
+    }
+}
+
+/// Splits a template like `{client} {method} {path} {status}` into literal
+/// text and `{field}` placeholders.
+fn parse_template(template: &str) -> anyhow::Result<Vec<Segment>> {
+    let mut segments = Vec::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .context("unclosed `{` in access log template")?;
+
+        if start > 0 {
+            segments.push(Segment::Literal(rest[..start].to_owned()));
+        }
+
+        let name = &rest[start + 1..end];
+        let field =
+            Field::parse(name).with_context(|| format!("unknown access log field {{{name}}}"))?;
+        segments.push(Segment::Field(field));
+
+        rest = &rest[end + 1..];
+    }
+
+    if !rest.is_empty() {
+        segments.push(Segment::Literal(rest.to_owned()));
+    }
+
+    Ok(segments)
+}
+
+fn or_dash(value: Option<&str>) -> Cow<'_, str> {
+    Cow::Borrowed(value.unwrap_or("-"))
+}
+
+fn serialize_time<S: Serializer>(time: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&time.to_rfc3339_opts(SecondsFormat::Millis, true))
+}
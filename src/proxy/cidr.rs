@@ -0,0 +1,142 @@
+use anyhow::Context;
+use std::net::IpAddr;
+
+/// An address range like `10.0.0.0/8`, a plain address matches only itself.
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+
+        let addr: IpAddr = addr
+            .parse()
+            .with_context(|| format!("invalid address {value}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|p| *p <= max)
+                .with_context(|| format!("invalid prefix {value}"))?,
+            None => max,
+        };
+
+        Ok(Self { addr, prefix })
+    }
+
+    /// Parses a comma-separated list, empty entries are skipped.
+    pub fn parse_list(value: &str) -> anyhow::Result<Vec<Self>> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(Self::parse)
+            .collect()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // ipv4 clients on a dual-stack listener show up as ::ffff:a.b.c.d
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(u32::from(net).into(), u32::from(ip).into(), 32, self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(u128::from(net), u128::from(ip), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_eq(a: u128, b: u128, bits: u8, prefix: u8) -> bool {
+    if prefix == 0 {
+        return true;
+    }
+    let shift = u32::from(bits - prefix);
+    (a >> shift) == (b >> shift)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_prefixes() {
+        let net = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(net.contains(ip("10.1.255.3")));
+        assert!(!net.contains(ip("10.2.0.1")));
+
+        let v6 = Cidr::parse("fd00:1::/32").unwrap();
+        assert!(v6.contains(ip("fd00:1::5")));
+        assert!(!v6.contains(ip("fd00:2::5")));
+    }
+
+    #[test]
+    fn zero_prefix_matches_its_family() {
+        let any = Cidr::parse("0.0.0.0/0").unwrap();
+        assert!(any.contains(ip("203.0.113.9")));
+        assert!(!any.contains(ip("2001:db8::1")));
+
+        let any_v6 = Cidr::parse("::/0").unwrap();
+        assert!(any_v6.contains(ip("2001:db8::1")));
+        assert!(!any_v6.contains(ip("203.0.113.9")));
+    }
+
+    #[test]
+    fn full_prefix_and_plain_address_match_only_themselves() {
+        for value in ["192.0.2.7/32", "192.0.2.7"] {
+            let host = Cidr::parse(value).unwrap();
+            assert!(host.contains(ip("192.0.2.7")), "{value}");
+            assert!(!host.contains(ip("192.0.2.8")), "{value}");
+        }
+
+        let host = Cidr::parse("2001:db8::1/128").unwrap();
+        assert!(host.contains(ip("2001:db8::1")));
+        assert!(!host.contains(ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn ipv4_mapped_clients_match_ipv4_ranges() {
+        let net = Cidr::parse("10.0.0.0/8").unwrap();
+        assert!(net.contains(ip("::ffff:10.3.4.5")));
+        assert!(!net.contains(ip("::ffff:11.3.4.5")));
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        for value in [
+            "",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "10.0.0/8",
+            "example.com/8",
+        ] {
+            assert!(Cidr::parse(value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn parses_lists() {
+        let list = Cidr::parse_list(" 10.0.0.0/8, ,::1 ").unwrap();
+        assert_eq!(list.len(), 2);
+        assert!(Cidr::parse_list("10.0.0.0/8,nope").is_err());
+    }
+}
@@ -2,17 +2,47 @@ use pingora::Result;
 use pingora::lb::Backend;
 use pingora::lb::discovery::ServiceDiscovery;
 use std::collections::BTreeSet;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use tokio::net::TcpSocket;
 
+use crate::metrics;
+
+/// Longest a health check waits for an upstream to accept the connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Result of the last health check of each upstream.
+pub type Health = Arc<RwLock<HashMap<SocketAddr, Check>>>;
+
+#[derive(Clone, Copy)]
+pub struct Check {
+    pub up: bool,
+    /// Time to connect, only for upstreams that are up.
+    pub latency: Option<Duration>,
+}
+
 pub struct PingDiscovery {
+    domain: String,
     upstreams: Vec<SocketAddr>,
+    drained: Arc<RwLock<HashSet<SocketAddr>>>,
+    health: Health,
 }
 
 impl PingDiscovery {
-    pub fn new(upstreams: Vec<SocketAddr>) -> Self {
-        Self { upstreams }
+    pub fn new(
+        domain: String,
+        upstreams: Vec<SocketAddr>,
+        drained: Arc<RwLock<HashSet<SocketAddr>>>,
+        health: Health,
+    ) -> Self {
+        Self {
+            domain,
+            upstreams,
+            drained,
+            health,
+        }
     }
 }
 
@@ -20,8 +50,17 @@ impl PingDiscovery {
 impl ServiceDiscovery for PingDiscovery {
     async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
         let mut timings = Vec::new();
+        let mut health = HashMap::new();
 
         for upstream in self.upstreams.iter() {
+            if self.drained.read().unwrap().contains(upstream) {
+                // not checked while drained, so its last result would be stale
+                metrics::BACKEND_UP
+                    .remove_label_values(&[&self.domain, &upstream.to_string()])
+                    .ok();
+                continue;
+            }
+
             let socket = match TcpSocket::new_v4() {
                 Ok(socket) => socket,
                 Err(err) => {
@@ -30,18 +69,35 @@ impl ServiceDiscovery for PingDiscovery {
                 }
             };
 
-            let start = std::time::Instant::now();
-            match socket.connect(*upstream).await {
-                Ok(_) => (),
-                Err(_) => continue,
-            };
+            let backend_up =
+                metrics::BACKEND_UP.with_label_values(&[&self.domain, &upstream.to_string()]);
 
+            let start = std::time::Instant::now();
+            let up = matches!(
+                tokio::time::timeout(CONNECT_TIMEOUT, socket.connect(*upstream)).await,
+                Ok(Ok(_))
+            );
             let elapsed = start.elapsed();
 
+            backend_up.set(i64::from(up));
+            health.insert(
+                *upstream,
+                Check {
+                    up,
+                    latency: up.then_some(elapsed),
+                },
+            );
+
+            if !up {
+                continue;
+            }
+
             timings.push((*upstream, elapsed));
         }
 
-        timings.sort_by(|(_, a), (_, b)| a.cmp(b));
+        *self.health.write().unwrap() = health;
+
+        timings.sort_by_key(|(_, elapsed)| *elapsed);
 
         tracing::debug!("discovery results: {:?}", timings);
 
@@ -1,17 +1,44 @@
 use pingora::lb::selection::RoundRobin;
 use pingora::lb::{Backend, Backends};
-use std::collections::HashMap;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
-use super::discovery::PingDiscovery;
+use super::discovery::{Health, PingDiscovery};
+use super::route::Route;
 use crate::config::provider::Value;
+use crate::metrics;
 
 type LoadBalancer = pingora::lb::LoadBalancer<RoundRobin>;
 
 #[derive(Default, Clone)]
 pub struct Gateway {
     inner: Arc<RwLock<GatewayInner>>,
+    /// Backends taken out of rotation through the admin api, kept across
+    /// config updates.
+    drained: Arc<std::sync::RwLock<HashSet<SocketAddr>>>,
+}
+
+/// Current state of a routed domain, as shown by the admin api.
+#[derive(Serialize)]
+pub struct RouteStatus {
+    pub domain: String,
+    pub tls: bool,
+    pub backends: Vec<BackendStatus>,
+}
+
+#[derive(Serialize)]
+pub struct BackendStatus {
+    pub addr: SocketAddr,
+    /// Result of the last health check, `None` before the first one or while drained.
+    pub healthy: Option<bool>,
+    /// Connect time of the last health check.
+    pub latency_ms: Option<f64>,
+    /// Receives the traffic, only the fastest healthy backend is selected.
+    pub selected: bool,
+    pub drained: bool,
 }
 
 impl Gateway {
@@ -19,38 +46,180 @@ impl Gateway {
         let mut entries = HashMap::new();
 
         for (domain, config) in upstreams {
-            let discovery = PingDiscovery::new(config.addrs);
+            let previous = self.route(&domain).await;
+            let (route, errors) = Route::reload(&config, previous.as_deref());
+            for err in &errors {
+                tracing::error!("failed to load upstream tls for {domain}: {err:?}");
+            }
+            if route.upstream_tls_failed {
+                tracing::error!(
+                    "no upstream tls certificates to fall back to for {domain}, tls connections to its upstreams will fail"
+                );
+            } else if !errors.is_empty() {
+                tracing::error!("keeping the previous upstream tls certificates for {domain}");
+            }
+
+            if route.tls
+                && route.options.upstream_tls.skip_verify
+                && !self.skips_verify(&domain).await
+            {
+                tracing::warn!(
+                    "upstream certificate verification is DISABLED for {domain}, traffic to it can be intercepted"
+                );
+            }
+
+            let health = Health::default();
+            let discovery = PingDiscovery::new(
+                domain.clone(),
+                config.addrs.clone(),
+                self.drained.clone(),
+                health.clone(),
+            );
             let backends = Backends::new(Box::new(discovery));
-            let lb = LoadBalancer::from_backends(backends);
+            let lb = Arc::new(LoadBalancer::from_backends(backends));
 
-            entries.insert(domain, (lb, config.tls));
+            let entry = Entry {
+                lb,
+                route: Arc::new(route),
+                addrs: config.addrs,
+                health,
+            };
+            entries.insert(domain, entry);
         }
 
-        for (domain, (lb, _)) in entries.iter() {
-            if let Err(err) = lb.update().await {
+        for (domain, entry) in entries.iter() {
+            if let Err(err) = entry.lb.update().await {
                 tracing::warn!("failed to update backends for {domain}: {err:?}");
             }
         }
 
-        let mut inner = self.inner.write().await;
-        inner.entries = entries;
+        let previous = std::mem::replace(&mut self.inner.write().await.entries, entries);
+
+        // health series of dropped routes and backends would otherwise stay forever
+        let inner = self.inner.read().await;
+        for (domain, entry) in previous {
+            let current = inner.entries.get(&domain);
+            for addr in entry.addrs {
+                if !current.is_some_and(|current| current.addrs.contains(&addr)) {
+                    metrics::BACKEND_UP
+                        .remove_label_values(&[&domain, &addr.to_string()])
+                        .ok();
+                }
+            }
+        }
     }
 
-    pub async fn process(&self, domain: &str) -> Option<(Backend, bool)> {
+    pub async fn process(&self, domain: &str) -> Option<(Backend, Arc<Route>)> {
         let inner = self.inner.read().await;
         inner.process(domain)
     }
+
+    pub async fn routes(&self) -> Vec<RouteStatus> {
+        let inner = self.inner.read().await;
+        let drained = self.drained.read().unwrap().clone();
+
+        let mut routes: Vec<_> = inner
+            .entries
+            .iter()
+            .map(|(domain, entry)| {
+                let selected = entry.lb.backends().get_backend();
+                let health = entry.health.read().unwrap();
+
+                let backends = entry
+                    .addrs
+                    .iter()
+                    .map(|addr| BackendStatus {
+                        addr: *addr,
+                        healthy: health.get(addr).map(|check| check.up),
+                        latency_ms: health
+                            .get(addr)
+                            .and_then(|check| check.latency)
+                            .map(|latency| latency.as_secs_f64() * 1000.0),
+                        selected: selected.iter().any(|b| b.addr.as_inet() == Some(addr)),
+                        drained: drained.contains(addr),
+                    })
+                    .collect();
+
+                RouteStatus {
+                    domain: domain.clone(),
+                    tls: entry.route.tls,
+                    backends,
+                }
+            })
+            .collect();
+
+        routes.sort_by(|a, b| a.domain.cmp(&b.domain));
+        routes
+    }
+
+    /// Takes `addr` out of rotation, or puts it back. Returns `false` if no
+    /// route uses it.
+    pub async fn set_drained(&self, addr: SocketAddr, drained: bool) -> bool {
+        // health checks run without the lock, a queued config update would
+        // otherwise hold up every request behind them
+        let affected: Vec<_> = self
+            .inner
+            .read()
+            .await
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.addrs.contains(&addr))
+            .map(|(domain, entry)| (domain.clone(), entry.lb.clone()))
+            .collect();
+
+        if affected.is_empty() {
+            return false;
+        }
+
+        {
+            let mut set = self.drained.write().unwrap();
+            if drained {
+                set.insert(addr);
+            } else {
+                set.remove(&addr);
+            }
+        }
+
+        for (domain, lb) in affected {
+            if let Err(err) = lb.update().await {
+                tracing::warn!("failed to update backends for {domain}: {err:?}");
+            }
+        }
+
+        true
+    }
+
+    async fn route(&self, domain: &str) -> Option<Arc<Route>> {
+        let inner = self.inner.read().await;
+        inner.entries.get(domain).map(|entry| entry.route.clone())
+    }
+
+    async fn skips_verify(&self, domain: &str) -> bool {
+        let inner = self.inner.read().await;
+        inner
+            .entries
+            .get(domain)
+            .map(|entry| entry.route.tls && entry.route.options.upstream_tls.skip_verify)
+            .unwrap_or(false)
+    }
+}
+
+struct Entry {
+    lb: Arc<LoadBalancer>,
+    route: Arc<Route>,
+    addrs: Vec<SocketAddr>,
+    health: Health,
 }
 
 #[derive(Default)]
 struct GatewayInner {
-    entries: HashMap<String, (LoadBalancer, bool)>,
+    entries: HashMap<String, Entry>,
 }
 
 impl GatewayInner {
-    pub fn process(&self, domain: &str) -> Option<(Backend, bool)> {
-        let (lb, tls) = self.entries.get(domain)?;
-        let backend = lb.select(b"", 64)?;
-        Some((backend, *tls))
+    pub fn process(&self, domain: &str) -> Option<(Backend, Arc<Route>)> {
+        let entry = self.entries.get(domain)?;
+        let backend = entry.lb.select(b"", 64)?;
+        Some((backend, entry.route.clone()))
     }
 }
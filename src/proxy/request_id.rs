@@ -0,0 +1,236 @@
+use anyhow::Context;
+use openssl::rand::rand_bytes;
+use std::net::IpAddr;
+
+use super::cidr::Cidr;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+const MAX_REQUEST_ID_LEN: usize = 200;
+
+/// Request correlation settings, from `TRUSTED_PROXIES` and `TRACEPARENT`.
+pub struct RequestIds {
+    trusted: Vec<Cidr>,
+    traceparent: bool,
+}
+
+/// W3C trace context of a request, with swarmly's own span as the parent
+/// passed upstream.
+pub struct TraceContext {
+    pub trace_id: String,
+    pub span_id: String,
+    /// Span id of the caller, if the request arrived with a `traceparent`.
+    pub parent_id: Option<String>,
+    pub flags: u8,
+}
+
+impl RequestIds {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let trusted = match std::env::var("TRUSTED_PROXIES") {
+            Ok(value) => Cidr::parse_list(&value).context("invalid TRUSTED_PROXIES")?,
+            Err(_) => Vec::new(),
+        };
+
+        let traceparent = std::env::var("TRACEPARENT")
+            .map(|v| v.trim() == "true")
+            .unwrap_or(false);
+
+        Ok(Self {
+            trusted,
+            traceparent,
+        })
+    }
+
+    fn is_trusted(&self, client: Option<IpAddr>) -> bool {
+        client.is_some_and(|ip| self.trusted.iter().any(|cidr| cidr.contains(ip)))
+    }
+
+    /// The incoming request id if `client` is a trusted proxy, a new one otherwise.
+    pub fn request_id(&self, client: Option<IpAddr>, incoming: Option<&str>) -> String {
+        match incoming {
+            Some(id) if self.is_trusted(client) && is_valid_request_id(id) => id.to_owned(),
+            _ => random_hex::<16>(),
+        }
+    }
+
+    /// Continues the incoming trace, or starts a new one. `None` unless
+    /// `TRACEPARENT` is enabled.
+    pub fn trace(&self, incoming: Option<&str>) -> Option<TraceContext> {
+        if !self.traceparent {
+            return None;
+        }
+
+        Some(TraceContext::from_incoming(incoming))
+    }
+}
+
+impl TraceContext {
+    /// A new span in the trace of the incoming `traceparent`, or in a new
+    /// trace if there is none or it is invalid.
+    pub fn from_incoming(incoming: Option<&str>) -> Self {
+        match incoming.and_then(Self::parse) {
+            Some(parent) => Self {
+                trace_id: parent.trace_id,
+                span_id: random_hex::<8>(),
+                parent_id: Some(parent.span_id),
+                flags: parent.flags,
+            },
+            None => Self {
+                trace_id: random_hex::<16>(),
+                span_id: random_hex::<8>(),
+                parent_id: None,
+                flags: 0x01,
+            },
+        }
+    }
+
+    /// Parses `00-<trace id>-<span id>-<flags>`, later versions are read the
+    /// same way as the spec asks.
+    fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let span_id = parts.next()?;
+        let flags = parts.next()?;
+
+        if version.len() != 2 || version == "ff" || !is_lower_hex(version) {
+            return None;
+        }
+        if version == "00" && parts.next().is_some() {
+            return None;
+        }
+        if trace_id.len() != 32 || !is_lower_hex(trace_id) || is_zero(trace_id) {
+            return None;
+        }
+        if span_id.len() != 16 || !is_lower_hex(span_id) || is_zero(span_id) {
+            return None;
+        }
+        if flags.len() != 2 || !is_lower_hex(flags) {
+            return None;
+        }
+
+        Some(Self {
+            trace_id: trace_id.to_owned(),
+            span_id: span_id.to_owned(),
+            parent_id: None,
+            flags: u8::from_str_radix(flags, 16).ok()?,
+        })
+    }
+
+    pub fn header_value(&self) -> String {
+        format!("00-{}-{}-{:02x}", self.trace_id, self.span_id, self.flags)
+    }
+}
+
+/// Incoming ids end up in logs and upstream headers, so only short printable
+/// values are taken over.
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+fn random_hex<const N: usize>() -> String {
+    let mut bytes = [0u8; N];
+    rand_bytes(&mut bytes).expect("failed to generate random id");
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn is_lower_hex(value: &str) -> bool {
+    value
+        .bytes()
+        .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn is_zero(value: &str) -> bool {
+    value.bytes().all(|b| b == b'0')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const SPAN_ID: &str = "00f067aa0ba902b7";
+
+    #[test]
+    fn parses_traceparent() {
+        let trace = TraceContext::parse(&format!("00-{TRACE_ID}-{SPAN_ID}-01")).unwrap();
+        assert_eq!(trace.trace_id, TRACE_ID);
+        assert_eq!(trace.span_id, SPAN_ID);
+        assert_eq!(trace.flags, 0x01);
+        assert_eq!(trace.header_value(), format!("00-{TRACE_ID}-{SPAN_ID}-01"));
+    }
+
+    #[test]
+    fn reads_later_versions_like_version_00() {
+        let trace = TraceContext::parse(&format!("cc-{TRACE_ID}-{SPAN_ID}-00-extra")).unwrap();
+        assert_eq!(trace.trace_id, TRACE_ID);
+        assert_eq!(trace.flags, 0x00);
+    }
+
+    #[test]
+    fn rejects_invalid_traceparents() {
+        let zero_trace = "0".repeat(32);
+        let zero_span = "0".repeat(16);
+
+        for value in [
+            String::new(),
+            format!("ff-{TRACE_ID}-{SPAN_ID}-01"),
+            format!("0-{TRACE_ID}-{SPAN_ID}-01"),
+            format!("0g-{TRACE_ID}-{SPAN_ID}-01"),
+            format!("00-{TRACE_ID}-{SPAN_ID}-01-extra"),
+            format!("00-{zero_trace}-{SPAN_ID}-01"),
+            format!("00-{TRACE_ID}-{zero_span}-01"),
+            format!("00-{}-{SPAN_ID}-01", TRACE_ID.to_uppercase()),
+            format!("00-{TRACE_ID}a-{SPAN_ID}-01"),
+            format!("00-{TRACE_ID}-{SPAN_ID}0-01"),
+            format!("00-{TRACE_ID}-{SPAN_ID}-1"),
+            format!("00-{TRACE_ID}-{SPAN_ID}"),
+        ] {
+            assert!(TraceContext::parse(&value).is_none(), "{value}");
+        }
+    }
+
+    #[test]
+    fn continues_incoming_trace() {
+        let trace = TraceContext::from_incoming(Some(&format!("00-{TRACE_ID}-{SPAN_ID}-00")));
+        assert_eq!(trace.trace_id, TRACE_ID);
+        assert_eq!(trace.parent_id.as_deref(), Some(SPAN_ID));
+        assert_ne!(trace.span_id, SPAN_ID);
+        assert_eq!(trace.flags, 0x00);
+
+        let trace = TraceContext::from_incoming(Some("garbage"));
+        assert_ne!(trace.trace_id, TRACE_ID);
+        assert_eq!(trace.parent_id, None);
+        assert_eq!(trace.flags, 0x01);
+    }
+
+    #[test]
+    fn validates_request_ids() {
+        assert!(is_valid_request_id("abc-123"));
+        assert!(is_valid_request_id(&"a".repeat(MAX_REQUEST_ID_LEN)));
+
+        assert!(!is_valid_request_id(""));
+        assert!(!is_valid_request_id(&"a".repeat(MAX_REQUEST_ID_LEN + 1)));
+        assert!(!is_valid_request_id("abc 123"));
+        assert!(!is_valid_request_id("abc\r\nx-injected: 1"));
+        assert!(!is_valid_request_id("ïd"));
+    }
+
+    #[test]
+    fn takes_request_ids_only_from_trusted_proxies() {
+        let ids = RequestIds {
+            trusted: Cidr::parse_list("10.0.0.0/8").unwrap(),
+            traceparent: false,
+        };
+
+        let trusted = Some("10.0.0.2".parse().unwrap());
+        let untrusted = Some("192.0.2.1".parse().unwrap());
+
+        assert_eq!(ids.request_id(trusted, Some("abc")), "abc");
+        assert_ne!(ids.request_id(untrusted, Some("abc")), "abc");
+        assert_ne!(ids.request_id(None, Some("abc")), "abc");
+        assert_ne!(ids.request_id(trusted, Some("a b")), "a b");
+        assert!(ids.trace(Some("anything")).is_none());
+    }
+}
@@ -0,0 +1,102 @@
+use anyhow::Context;
+use pingora::tls::pkey::PKey;
+use pingora::tls::x509::X509;
+use pingora::utils::tls::CertKey;
+use std::sync::Arc;
+
+use crate::config::provider::{RouteOptions, ServiceConfig, UpstreamTls};
+
+/// Settings shared by every request routed to a domain.
+pub struct Route {
+    pub tls: bool,
+    pub options: RouteOptions,
+    pub upstream_ca: Option<Arc<Box<[X509]>>>,
+    pub upstream_cert: Option<Arc<CertKey>>,
+    /// The upstream CA or client cert failed to load and there was no
+    /// earlier copy to keep, TLS connections to the upstream are refused.
+    pub upstream_tls_failed: bool,
+}
+
+impl Route {
+    pub fn load(config: &ServiceConfig) -> anyhow::Result<Self> {
+        let upstream_tls = &config.options.upstream_tls;
+
+        Ok(Self {
+            tls: config.tls,
+            options: config.options.clone(),
+            upstream_ca: load_ca(upstream_tls)?,
+            upstream_cert: load_client_cert(upstream_tls)?,
+            upstream_tls_failed: false,
+        })
+    }
+
+    /// Like [`Route::load`], but an upstream CA or client cert that fails to
+    /// load doesn't drop the route. The copy loaded by `previous` is kept
+    /// instead, or without one [`Route::upstream_tls_failed`] is set.
+    ///
+    /// Returns the load errors alongside the route.
+    pub fn reload(config: &ServiceConfig, previous: Option<&Route>) -> (Self, Vec<anyhow::Error>) {
+        let upstream_tls = &config.options.upstream_tls;
+        let mut errors = Vec::new();
+        let mut failed = false;
+
+        let upstream_ca = load_ca(upstream_tls).unwrap_or_else(|err| {
+            errors.push(err);
+            let kept = previous.and_then(|previous| previous.upstream_ca.clone());
+            failed |= kept.is_none();
+            kept
+        });
+
+        let upstream_cert = load_client_cert(upstream_tls).unwrap_or_else(|err| {
+            errors.push(err);
+            let kept = previous.and_then(|previous| previous.upstream_cert.clone());
+            failed |= kept.is_none();
+            kept
+        });
+
+        let route = Self {
+            tls: config.tls,
+            options: config.options.clone(),
+            upstream_ca,
+            upstream_cert,
+            upstream_tls_failed: failed,
+        };
+
+        (route, errors)
+    }
+}
+
+fn load_ca(upstream_tls: &UpstreamTls) -> anyhow::Result<Option<Arc<Box<[X509]>>>> {
+    let Some(path) = &upstream_tls.ca_path else {
+        return Ok(None);
+    };
+
+    let pem = std::fs::read(path)
+        .with_context(|| format!("failed to read upstream ca {}", path.display()))?;
+    let cas = X509::stack_from_pem(&pem).context("failed to parse upstream ca")?;
+    Ok(Some(Arc::new(cas.into_boxed_slice())))
+}
+
+fn load_client_cert(upstream_tls: &UpstreamTls) -> anyhow::Result<Option<Arc<CertKey>>> {
+    let Some((cert_path, key_path)) = &upstream_tls.client_cert else {
+        return Ok(None);
+    };
+
+    let cert = std::fs::read(cert_path).with_context(|| {
+        format!(
+            "failed to read upstream client cert {}",
+            cert_path.display()
+        )
+    })?;
+    let key = std::fs::read(key_path)
+        .with_context(|| format!("failed to read upstream client key {}", key_path.display()))?;
+
+    let certs = X509::stack_from_pem(&cert).context("failed to parse upstream client cert")?;
+    let key = PKey::private_key_from_pem(&key).context("failed to parse upstream client key")?;
+
+    if certs.is_empty() {
+        anyhow::bail!("upstream client cert {} is empty", cert_path.display());
+    }
+
+    Ok(Some(Arc::new(CertKey::new(certs, key))))
+}
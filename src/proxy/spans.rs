@@ -0,0 +1,278 @@
+use opentelemetry::trace::{
+    Span, SpanContext, SpanId, SpanKind, Status, TraceContextExt, TraceFlags, TraceId, TraceState,
+    Tracer,
+};
+use opentelemetry::{Context, KeyValue};
+use pingora::protocols::Digest;
+use pingora::proxy::Session;
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+
+use super::request_id::TraceContext;
+use crate::telemetry;
+use crate::tls::HandshakeInfo;
+
+/// Points in the proxy lifecycle, recorded as the `ProxyHttp` hooks run and
+/// turned into spans once the request is done.
+#[derive(Default)]
+pub struct Timings {
+    pub start: Option<SystemTime>,
+    pub routed: Option<SystemTime>,
+    pub upstream_start: Option<SystemTime>,
+    pub connected: Option<SystemTime>,
+    pub connection_reused: bool,
+    /// Upstream TLS handshake of a fresh connection.
+    pub upstream_tls: Option<(SystemTime, SystemTime)>,
+    pub first_byte: Option<SystemTime>,
+}
+
+impl Timings {
+    pub fn connected(&mut self, reused: bool, digest: Option<&Digest>) {
+        self.connected = Some(SystemTime::now());
+        self.connection_reused = reused;
+
+        if !reused && digest.is_some_and(|d| d.ssl_digest.is_some()) {
+            self.upstream_tls = digest.and_then(handshake_times);
+        }
+    }
+}
+
+/// Exports the spans of a finished request. The request span takes the ids
+/// of `trace`, so it matches the `traceparent` sent upstream.
+pub fn export(
+    session: &Session,
+    timings: &Timings,
+    trace: Option<&TraceContext>,
+    status: u16,
+    attributes: Vec<KeyValue>,
+) {
+    // the handshake belongs to the connection, it's reported with the first
+    // request sent over it
+    let handshake = session.digest().and_then(|digest| {
+        let info = digest
+            .ssl_digest
+            .as_ref()?
+            .extension
+            .get::<HandshakeInfo>()?;
+        if info.traced.swap(true, Ordering::Relaxed) {
+            return None;
+        }
+        handshake_times(digest)
+    });
+
+    record(
+        &telemetry::tracer(),
+        session.req_header().method.as_str(),
+        timings,
+        handshake,
+        trace,
+        status,
+        attributes,
+    );
+}
+
+fn record<T>(
+    tracer: &T,
+    method: &str,
+    timings: &Timings,
+    handshake: Option<(SystemTime, SystemTime)>,
+    trace: Option<&TraceContext>,
+    status: u16,
+    attributes: Vec<KeyValue>,
+) where
+    T: Tracer,
+    T::Span: Send + Sync + 'static,
+{
+    let Some(start) = timings.start else {
+        return;
+    };
+    let end = SystemTime::now();
+
+    let mut builder = tracer
+        .span_builder(method.to_owned())
+        .with_kind(SpanKind::Server)
+        .with_start_time(start)
+        .with_attributes(attributes);
+
+    let mut parent = Context::new();
+    if let Some(trace) = trace {
+        builder.trace_id = TraceId::from_hex(&trace.trace_id).ok();
+        builder.span_id = SpanId::from_hex(&trace.span_id).ok();
+
+        if let Some(parent_id) = trace
+            .parent_id
+            .as_deref()
+            .and_then(|id| SpanId::from_hex(id).ok())
+        {
+            let remote = SpanContext::new(
+                TraceId::from_hex(&trace.trace_id).unwrap_or(TraceId::INVALID),
+                parent_id,
+                TraceFlags::new(trace.flags),
+                true,
+                TraceState::default(),
+            );
+            parent = parent.with_remote_span_context(remote);
+        }
+    }
+
+    let mut request = builder.start_with_context(tracer, &parent);
+    if status >= 500 || status == 0 {
+        request.set_status(Status::error(format!("status {status}")));
+    }
+    let cx = Context::new().with_span(request);
+
+    let child =
+        |name: &'static str, from: SystemTime, to: SystemTime, attributes: Vec<KeyValue>| {
+            let mut span = tracer
+                .span_builder(name)
+                .with_kind(SpanKind::Internal)
+                .with_start_time(from)
+                .with_attributes(attributes)
+                .start_with_context(tracer, &cx);
+            span.end_with_timestamp(to);
+        };
+
+    if let Some((from, to)) = handshake {
+        child("tls handshake", from, to, Vec::new());
+    }
+
+    if let Some(routed) = timings.routed {
+        child("route", start, routed, Vec::new());
+    }
+
+    if let (Some(from), Some(to)) = (timings.upstream_start, timings.connected) {
+        child(
+            "upstream connect",
+            from,
+            to,
+            vec![KeyValue::new(
+                "swarmly.connection_reused",
+                timings.connection_reused,
+            )],
+        );
+    }
+
+    if let Some((from, to)) = timings.upstream_tls {
+        child("upstream tls handshake", from, to, Vec::new());
+    }
+
+    if let (Some(from), Some(to)) = (timings.connected, timings.first_byte) {
+        child("upstream time to first byte", from, to, Vec::new());
+    }
+
+    if let Some(first_byte) = timings.first_byte {
+        child("response", first_byte, end, Vec::new());
+    }
+
+    cx.span().end_with_timestamp(end);
+}
+
+/// When the transport and the TLS layer of a connection were established.
+fn handshake_times(digest: &Digest) -> Option<(SystemTime, SystemTime)> {
+    let mut layers = digest.timing_digest.iter().flatten();
+    let transport = layers.next()?.established_ts;
+    let tls = layers.last()?.established_ts;
+    Some((transport, tls))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    /// Accepts one OTLP/HTTP export, returns its request line and body.
+    fn receive_export(listener: TcpListener) -> (String, Vec<u8>) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':')
+                && name.eq_ignore_ascii_case("content-length")
+            {
+                length = value.trim().parse().unwrap();
+            }
+        }
+
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        reader
+            .get_mut()
+            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+            .unwrap();
+
+        (request_line, body)
+    }
+
+    fn bytes(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    #[test]
+    fn exports_request_span_in_the_incoming_trace() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        let receiver = std::thread::spawn(move || receive_export(listener));
+
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+            .unwrap();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter)
+            .build();
+
+        let trace = TraceContext::from_incoming(Some(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        ));
+        let timings = Timings {
+            start: Some(SystemTime::now()),
+            ..Default::default()
+        };
+
+        record(
+            &provider.tracer("test"),
+            "GET",
+            &timings,
+            None,
+            Some(&trace),
+            200,
+            vec![KeyValue::new("swarmly.domain", "example.com")],
+        );
+
+        let (request_line, body) = receiver.join().unwrap();
+        provider.shutdown().unwrap();
+
+        assert!(
+            request_line.starts_with("POST /v1/traces "),
+            "{request_line}"
+        );
+        assert_eq!(trace.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert!(contains(&body, &bytes(&trace.trace_id)));
+        assert!(contains(&body, &bytes(&trace.span_id)));
+        assert!(contains(&body, &bytes("00f067aa0ba902b7")));
+        assert!(contains(&body, b"GET"));
+        assert!(contains(&body, b"example.com"));
+    }
+}
@@ -1,10 +1,58 @@
 use anyhow::Context;
-use redis::AsyncCommands;
-use redis::aio::ConnectionManager;
+use futures_util::{Stream, StreamExt};
+use redis::{AsyncCommands, RedisResult, Script};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use self::connection::Connection;
+pub use self::monitor::{FailoverWatcher, RedisMonitor};
+
+mod connection;
+mod monitor;
+
+type RecoverCallback = dyn Fn() -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync + 'static;
+
+const FENCE_MAGIC: &[u8] = b"SWF1";
+const FENCE_WIDTH: usize = 20;
+
+const DEL_IF_EQ: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+";
+
+const EXPIRE_IF_EQ: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('EXPIRE', KEYS[1], ARGV[2])
+end
+return 0
+";
+
+const SET_FENCED: &str = r"
+local current = redis.call('GET', KEYS[1])
+if current and string.sub(current, 1, 4) == 'SWF1' then
+    local stored = tonumber(string.sub(current, 5, 24))
+    if stored and stored > tonumber(ARGV[2]) then
+        return 0
+    end
+end
+redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[3])
+return 1
+";
 
 #[derive(Clone)]
 pub struct RedisClient {
-    manager: ConnectionManager,
+    conn: Arc<Connection>,
+    namespace: String,
+    health: Arc<Health>,
+}
+
+struct Health {
+    healthy: AtomicBool,
+    callbacks: RwLock<Vec<Box<RecoverCallback>>>,
 }
 
 impl RedisClient {
@@ -14,42 +62,301 @@ impl RedisClient {
             Err(_) => return Ok(None),
         };
 
-        let client = redis::Client::open(url).context("failed to create redis client")?;
-        let manager = ConnectionManager::new(client)
+        let conn = Connection::open(url.trim()).await?;
+
+        // every key is prefixed, so several stacks can share one redis
+        let namespace = match std::env::var("REDIS_NAMESPACE") {
+            Ok(ns) if !ns.trim().is_empty() => format!("{}:", ns.trim()),
+            _ => String::new(),
+        };
+
+        let health = Arc::new(Health {
+            healthy: AtomicBool::new(true),
+            callbacks: RwLock::new(Vec::new()),
+        });
+
+        Ok(Some(Self {
+            conn: Arc::new(conn),
+            namespace,
+            health,
+        }))
+    }
+
+    /// `false` while the last command or health check failed to reach redis.
+    pub fn is_healthy(&self) -> bool {
+        self.health.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Registers a callback [`RedisMonitor`] starts once redis is reachable again.
+    pub fn set_recover_callback<F, Fut>(&self, callback: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let boxed =
+            Box::new(move || Box::pin(callback()) as Pin<Box<dyn Future<Output = ()> + Send>>);
+
+        self.health.callbacks.write().unwrap().push(boxed);
+    }
+
+    /// `true` for a sentinel-managed master, whose failovers [`FailoverWatcher`] follows.
+    pub fn is_sentinel(&self) -> bool {
+        self.conn.is_sentinel()
+    }
+
+    /// Reconnects if the sentinels moved the master, a no-op otherwise.
+    pub async fn follow_failover(&self) {
+        self.conn.refresh().await;
+    }
+
+    /// Pings redis, running the recover callbacks when it comes back after an outage.
+    pub async fn check(&self) {
+        let mut conn = self.conn.handle();
+        let ping: RedisResult<String> = redis::cmd("PING").query_async(&mut conn).await;
+
+        match ping {
+            Ok(_) if !self.health.healthy.swap(true, Ordering::Relaxed) => {
+                tracing::info!("redis is reachable again, resuming distributed mode");
+
+                let futures: Vec<_> = self
+                    .health
+                    .callbacks
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .map(|cb| cb())
+                    .collect();
+
+                // recovery can issue certificates for minutes, the monitor keeps
+                // pinging and following failovers meanwhile
+                tokio::spawn(async move {
+                    for fut in futures {
+                        fut.await;
+                    }
+                });
+            }
+            Ok(_) => {}
+            Err(err) => self.mark_unhealthy(&err),
+        }
+    }
+
+    pub async fn publish(&self, channel: &str, payload: &[u8]) -> anyhow::Result<()> {
+        let mut conn = self.conn.handle();
+        self.track(conn.publish(self.key(channel), payload).await)
+            .context("redis PUBLISH failed")
+    }
+
+    /// Subscribes to `channel` on a dedicated connection, the stream ends
+    /// when that connection is lost.
+    pub async fn subscribe(
+        &self,
+        channel: &str,
+    ) -> anyhow::Result<impl Stream<Item = Vec<u8>> + Send + use<>> {
+        let mut pubsub = self
+            .conn
+            .pubsub_client()
+            .get_async_pubsub()
             .await
-            .context("failed to connect to redis")?;
+            .context("failed to open redis pub/sub connection")?;
 
-        Ok(Some(Self { manager }))
+        pubsub
+            .subscribe(self.key(channel))
+            .await
+            .context("redis SUBSCRIBE failed")?;
+
+        Ok(pubsub
+            .into_on_message()
+            .map(|msg| msg.get_payload_bytes().to_vec()))
+    }
+
+    fn track<T>(&self, result: RedisResult<T>) -> RedisResult<T> {
+        if let Err(err) = &result
+            && (err.is_io_error()
+                || err.is_connection_refusal()
+                || err.is_connection_dropped()
+                || err.is_timeout()
+                || err.is_cluster_error())
+        {
+            self.mark_unhealthy(err);
+        }
+        result
+    }
+
+    fn mark_unhealthy(&self, err: &redis::RedisError) {
+        if self.health.healthy.swap(false, Ordering::Relaxed) {
+            tracing::error!(
+                "redis is unreachable ({err}), serving cached certificates and pausing issuance"
+            );
+        }
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{key}", self.namespace)
     }
 
     pub async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
-        let mut conn = self.manager.clone();
-        conn.get(key).await.context("redis GET failed")
+        let mut conn = self.conn.handle();
+        self.track(conn.get(self.key(key)).await)
+            .context("redis GET failed")
     }
 
     pub async fn set(&self, key: &str, value: Vec<u8>, ttl_secs: u64) -> anyhow::Result<()> {
-        let mut conn = self.manager.clone();
-        conn.set_ex(key, value, ttl_secs)
-            .await
+        let mut conn = self.conn.handle();
+        self.track(conn.set_ex(self.key(key), value, ttl_secs).await)
             .context("redis SET EX failed")
     }
 
     pub async fn set_nx(&self, key: &str, value: Vec<u8>, ttl_secs: u64) -> anyhow::Result<bool> {
-        let mut conn = self.manager.clone();
-        let result: Option<String> = redis::cmd("SET")
-            .arg(key)
-            .arg(value)
-            .arg("NX")
-            .arg("EX")
-            .arg(ttl_secs)
-            .query_async(&mut conn)
-            .await
+        let mut conn = self.conn.handle();
+        let result: Option<String> = self
+            .track(
+                redis::cmd("SET")
+                    .arg(self.key(key))
+                    .arg(value)
+                    .arg("NX")
+                    .arg("EX")
+                    .arg(ttl_secs)
+                    .query_async(&mut conn)
+                    .await,
+            )
             .context("redis SET NX EX failed")?;
         Ok(result.is_some())
     }
 
-    pub async fn del(&self, key: &str) -> anyhow::Result<()> {
-        let mut conn = self.manager.clone();
-        conn.del(key).await.context("redis DEL failed")
+    pub async fn incr(&self, key: &str) -> anyhow::Result<u64> {
+        let mut conn = self.conn.handle();
+        self.track(conn.incr(self.key(key), 1).await)
+            .context("redis INCR failed")
+    }
+
+    /// Deletes `key` only if it still holds `value`.
+    pub async fn del_if_eq(&self, key: &str, value: &[u8]) -> anyhow::Result<bool> {
+        let mut conn = self.conn.handle();
+        let deleted: u64 = self
+            .track(
+                Script::new(DEL_IF_EQ)
+                    .key(self.key(key))
+                    .arg(value)
+                    .invoke_async(&mut conn)
+                    .await,
+            )
+            .context("redis compare-and-delete failed")?;
+        Ok(deleted == 1)
+    }
+
+    /// Resets the TTL of `key` only if it still holds `value`.
+    pub async fn expire_if_eq(
+        &self,
+        key: &str,
+        value: &[u8],
+        ttl_secs: u64,
+    ) -> anyhow::Result<bool> {
+        let mut conn = self.conn.handle();
+        let extended: u64 = self
+            .track(
+                Script::new(EXPIRE_IF_EQ)
+                    .key(self.key(key))
+                    .arg(value)
+                    .arg(ttl_secs)
+                    .invoke_async(&mut conn)
+                    .await,
+            )
+            .context("redis compare-and-expire failed")?;
+        Ok(extended == 1)
+    }
+
+    /// Writes `value` tagged with a fencing `token`, unless the stored value
+    /// carries a newer token. Returns `false` when the write was rejected.
+    pub async fn set_fenced(
+        &self,
+        key: &str,
+        value: &[u8],
+        token: u64,
+        ttl_secs: u64,
+    ) -> anyhow::Result<bool> {
+        let mut fenced = Vec::with_capacity(FENCE_MAGIC.len() + FENCE_WIDTH + value.len());
+        fenced.extend_from_slice(FENCE_MAGIC);
+        fenced.extend_from_slice(format!("{token:0width$}", width = FENCE_WIDTH).as_bytes());
+        fenced.extend_from_slice(value);
+
+        let mut conn = self.conn.handle();
+        let written: u64 = self
+            .track(
+                Script::new(SET_FENCED)
+                    .key(self.key(key))
+                    .arg(fenced)
+                    .arg(token)
+                    .arg(ttl_secs)
+                    .invoke_async(&mut conn)
+                    .await,
+            )
+            .context("redis fenced SET failed")?;
+        Ok(written == 1)
+    }
+
+    pub async fn del(&self, key: &str) -> anyhow::Result<bool> {
+        let mut conn = self.conn.handle();
+        let removed: u64 = self
+            .track(conn.del(self.key(key)).await)
+            .context("redis DEL failed")?;
+        Ok(removed > 0)
+    }
+
+    /// Keys starting with `prefix`, without the namespace. Not available on
+    /// Redis Cluster, where SCAN only walks a single node.
+    pub async fn keys_with_prefix(&self, prefix: &str) -> anyhow::Result<Vec<String>> {
+        if self.conn.is_cluster() {
+            anyhow::bail!("listing keys is not supported on redis cluster");
+        }
+
+        let pattern = format!("{}*", escape_glob(&self.key(prefix)));
+        let mut conn = self.conn.handle();
+        let mut cursor = 0u64;
+        let mut keys = Vec::new();
+
+        loop {
+            let (next, batch): (u64, Vec<String>) = self
+                .track(
+                    redis::cmd("SCAN")
+                        .arg(cursor)
+                        .arg("MATCH")
+                        .arg(&pattern)
+                        .arg("COUNT")
+                        .arg(500)
+                        .query_async(&mut conn)
+                        .await,
+                )
+                .context("redis SCAN failed")?;
+
+            keys.extend(
+                batch
+                    .into_iter()
+                    .filter_map(|key| key.strip_prefix(&self.namespace).map(str::to_owned)),
+            );
+
+            if next == 0 {
+                return Ok(keys);
+            }
+            cursor = next;
+        }
+    }
+}
+
+fn escape_glob(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Strips the fencing token written by [`RedisClient::set_fenced`], if any.
+pub fn strip_fence(buf: &[u8]) -> &[u8] {
+    match buf.strip_prefix(FENCE_MAGIC) {
+        Some(rest) if rest.len() >= FENCE_WIDTH => &rest[FENCE_WIDTH..],
+        _ => buf,
     }
 }
@@ -0,0 +1,439 @@
+use anyhow::Context;
+use redis::aio::{ConnectionLike, ConnectionManager};
+use redis::cluster::ClusterClient;
+use redis::cluster_async::ClusterConnection;
+use redis::sentinel::{SentinelClient, SentinelClientBuilder, SentinelServerType};
+use redis::{
+    Cmd, ConnectionAddr, IntoConnectionInfo, Pipeline, RedisFuture, TlsCertificates, TlsMode, Value,
+};
+use std::sync::{Arc, RwLock};
+
+/// Connection to a standalone server, a sentinel-managed master or a cluster.
+///
+/// The plain [`redis::Client`] next to each connection is used for pub/sub,
+/// which needs a dedicated connection to a single node.
+pub enum Connection {
+    Single(ConnectionManager, redis::Client),
+    Sentinel(Arc<Sentinel>),
+    Cluster(ClusterConnection, redis::Client),
+}
+
+/// Current master of a sentinel service, replaced when the sentinels report a failover.
+pub struct Sentinel {
+    service: String,
+    client: tokio::sync::Mutex<SentinelClient>,
+    master: RwLock<Master>,
+}
+
+struct Master {
+    addr: ConnectionAddr,
+    client: redis::Client,
+    manager: ConnectionManager,
+}
+
+/// Command handle taken from [`Connection`] for a single request.
+pub enum Handle {
+    Manager(ConnectionManager),
+    Cluster(ClusterConnection),
+}
+
+struct Settings {
+    username: Option<String>,
+    password: Option<String>,
+    certificates: Option<TlsCertificates>,
+}
+
+impl Connection {
+    /// Connects using `REDIS_URL`, see the README for the supported schemes.
+    pub async fn open(url: &str) -> anyhow::Result<Self> {
+        let settings = Settings::from_env()?;
+
+        let (scheme, rest) = url
+            .split_once("://")
+            .context("redis url must have a scheme")?;
+
+        match scheme {
+            "redis" | "rediss" | "unix" | "redis+unix" => Self::single(url, settings).await,
+            "redis+sentinel" | "rediss+sentinel" => {
+                Sentinel::connect(rest, scheme == "rediss+sentinel", settings)
+                    .await
+                    .map(|s| Self::Sentinel(Arc::new(s)))
+            }
+            "redis+cluster" | "rediss+cluster" => {
+                Self::cluster(rest, scheme == "rediss+cluster", settings).await
+            }
+            other => anyhow::bail!("unsupported redis url scheme {other}"),
+        }
+    }
+
+    /// Follows a sentinel failover, a no-op for other connection kinds.
+    pub async fn refresh(&self) {
+        if let Self::Sentinel(sentinel) = self {
+            sentinel.refresh().await;
+        }
+    }
+
+    pub fn handle(&self) -> Handle {
+        match self {
+            Self::Single(manager, _) => Handle::Manager(manager.clone()),
+            Self::Sentinel(sentinel) => Handle::Manager(sentinel.manager()),
+            Self::Cluster(cluster, _) => Handle::Cluster(cluster.clone()),
+        }
+    }
+
+    pub fn is_sentinel(&self) -> bool {
+        matches!(self, Self::Sentinel(_))
+    }
+
+    pub fn is_cluster(&self) -> bool {
+        matches!(self, Self::Cluster(..))
+    }
+
+    /// Client for a node that receives every pub/sub message.
+    pub fn pubsub_client(&self) -> redis::Client {
+        match self {
+            Self::Single(_, client) | Self::Cluster(_, client) => client.clone(),
+            Self::Sentinel(sentinel) => sentinel.master.read().unwrap().client.clone(),
+        }
+    }
+
+    async fn single(url: &str, settings: Settings) -> anyhow::Result<Self> {
+        let client = node_client(
+            url,
+            settings.username.as_deref(),
+            settings.password.as_deref(),
+            settings.certificates,
+        )?;
+
+        let manager = ConnectionManager::new(client.clone())
+            .await
+            .context("failed to connect to redis")?;
+
+        Ok(Self::Single(manager, client))
+    }
+
+    async fn cluster(rest: &str, tls: bool, settings: Settings) -> anyhow::Result<Self> {
+        let (userinfo, hosts, _) = split_url(rest)?;
+        let scheme = if tls { "rediss" } else { "redis" };
+
+        let nodes = hosts
+            .iter()
+            .map(|host| format!("{scheme}://{host}"))
+            .collect::<Vec<_>>();
+
+        let mut builder = ClusterClient::builder(nodes.clone());
+
+        let (username, password) = settings.credentials(userinfo);
+        let pubsub = node_client(
+            &nodes[0],
+            username.as_deref(),
+            password.as_deref(),
+            settings.certificates.clone(),
+        )?;
+
+        if let Some(username) = username {
+            builder = builder.username(username);
+        }
+        if let Some(password) = password {
+            builder = builder.password(password);
+        }
+        if tls {
+            builder = builder.tls(TlsMode::Secure);
+        }
+        if let Some(certs) = settings.certificates {
+            builder = builder.certs(certs);
+        }
+
+        let client = builder
+            .build()
+            .context("failed to create redis cluster client")?;
+        let connection = client
+            .get_async_connection()
+            .await
+            .context("failed to connect to redis cluster")?;
+
+        tracing::info!("connected to redis cluster via {} nodes", hosts.len());
+
+        Ok(Self::Cluster(connection, pubsub))
+    }
+}
+
+impl Sentinel {
+    async fn connect(rest: &str, tls: bool, settings: Settings) -> anyhow::Result<Self> {
+        let (userinfo, hosts, path) = split_url(rest)?;
+
+        let mut segments = path.split('/').filter(|s| !s.is_empty());
+        let service = segments
+            .next()
+            .context("redis sentinel url must name the master, e.g. redis+sentinel://host:26379/mymaster")?
+            .to_owned();
+        let db = segments
+            .next()
+            .map(|db| {
+                db.parse::<i64>()
+                    .with_context(|| format!("failed to parse redis db {db}"))
+            })
+            .transpose()?;
+
+        let scheme = if tls { "rediss" } else { "redis" };
+        let sentinels = hosts
+            .iter()
+            .map(|host| {
+                let host = if host.contains(':') {
+                    host.to_string()
+                } else {
+                    format!("{host}:26379")
+                };
+                format!("{scheme}://{host}")
+                    .into_connection_info()
+                    .map(|info| info.addr().clone())
+                    .with_context(|| format!("invalid sentinel address {host}"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let mut builder =
+            SentinelClientBuilder::new(sentinels, &service, SentinelServerType::Master)
+                .context("failed to create redis sentinel client")?;
+
+        let (username, password) = settings.credentials(userinfo);
+        if let Some(username) = username {
+            builder = builder.set_client_to_redis_username(username);
+        }
+        if let Some(password) = password {
+            builder = builder.set_client_to_redis_password(password);
+        }
+        if let Ok(username) = std::env::var("REDIS_SENTINEL_USERNAME") {
+            builder = builder.set_client_to_sentinel_username(username);
+        }
+        if let Ok(password) = std::env::var("REDIS_SENTINEL_PASSWORD") {
+            builder = builder.set_client_to_sentinel_password(password);
+        }
+        if let Some(db) = db {
+            builder = builder.set_client_to_redis_db(db);
+        }
+        if tls {
+            builder = builder
+                .set_client_to_redis_tls_mode(TlsMode::Secure)
+                .set_client_to_sentinel_tls_mode(TlsMode::Secure);
+        }
+        if let Some(certs) = settings.certificates {
+            builder = builder
+                .set_client_to_redis_certificates(certs.clone())
+                .set_client_to_sentinel_certificates(certs);
+        }
+
+        let mut client = builder
+            .build()
+            .context("failed to create redis sentinel client")?;
+        let master = connect_master(&mut client, &service).await?;
+
+        tracing::info!("redis sentinel master for {service} is {}", master.addr);
+
+        Ok(Self {
+            service,
+            client: tokio::sync::Mutex::new(client),
+            master: RwLock::new(master),
+        })
+    }
+
+    fn manager(&self) -> ConnectionManager {
+        self.master.read().unwrap().manager.clone()
+    }
+
+    /// Asks the sentinels for the current master and reconnects after a failover.
+    async fn refresh(&self) {
+        let mut client = self.client.lock().await;
+        let addr = match client.async_get_client().await {
+            Ok(master) => master.get_connection_info().addr().clone(),
+            Err(err) => {
+                tracing::warn!(
+                    "failed to query redis sentinels for {}: {err}",
+                    self.service
+                );
+                return;
+            }
+        };
+
+        if self.master.read().unwrap().addr == addr {
+            return;
+        }
+
+        match connect_master(&mut client, &self.service).await {
+            Ok(master) => {
+                tracing::warn!(
+                    "redis master for {} moved from {} to {}",
+                    self.service,
+                    self.master.read().unwrap().addr,
+                    master.addr
+                );
+                *self.master.write().unwrap() = master;
+            }
+            Err(err) => tracing::error!("failed to connect to new redis master: {err:#}"),
+        }
+    }
+}
+
+impl Settings {
+    fn from_env() -> anyhow::Result<Self> {
+        let read = |name: &str| -> anyhow::Result<Option<Vec<u8>>> {
+            match std::env::var(name) {
+                Ok(path) => std::fs::read(path.trim())
+                    .map(Some)
+                    .with_context(|| format!("failed to read {name} from {path}")),
+                Err(_) => Ok(None),
+            }
+        };
+
+        let root_cert = read("REDIS_TLS_CA")?;
+        let client_tls = match (read("REDIS_TLS_CERT")?, read("REDIS_TLS_KEY")?) {
+            (Some(client_cert), Some(client_key)) => Some(redis::ClientTlsConfig {
+                client_cert,
+                client_key,
+            }),
+            (None, None) => None,
+            _ => anyhow::bail!("REDIS_TLS_CERT and REDIS_TLS_KEY must be set together"),
+        };
+
+        let certificates =
+            (root_cert.is_some() || client_tls.is_some()).then_some(TlsCertificates {
+                client_tls,
+                root_cert,
+            });
+
+        let password = match std::env::var("REDIS_PASSWORD") {
+            Ok(password) => Some(password),
+            Err(_) => match std::env::var("REDIS_PASSWORD_FILE") {
+                Ok(path) => Some(
+                    std::fs::read_to_string(path.trim())
+                        .with_context(|| format!("failed to read redis password from {path}"))?
+                        .trim()
+                        .to_owned(),
+                ),
+                Err(_) => None,
+            },
+        };
+
+        Ok(Self {
+            username: std::env::var("REDIS_USERNAME").ok(),
+            password,
+            certificates,
+        })
+    }
+
+    /// Credentials from the environment, falling back to the url userinfo.
+    fn credentials(&self, userinfo: Option<&str>) -> (Option<String>, Option<String>) {
+        let (url_username, url_password) = match userinfo {
+            Some(info) => match info.split_once(':') {
+                Some((user, pass)) => (Some(user), Some(pass)),
+                None => (Some(info), None),
+            },
+            None => (None, None),
+        };
+
+        let username = self
+            .username
+            .clone()
+            .or(url_username.filter(|u| !u.is_empty()).map(str::to_owned));
+        let password = self
+            .password
+            .clone()
+            .or(url_password.filter(|p| !p.is_empty()).map(str::to_owned));
+
+        (username, password)
+    }
+}
+
+impl ConnectionLike for Handle {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            Self::Manager(conn) => conn.req_packed_command(cmd),
+            Self::Cluster(conn) => conn.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            Self::Manager(conn) => conn.req_packed_commands(cmd, offset, count),
+            Self::Cluster(conn) => conn.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Self::Manager(conn) => conn.get_db(),
+            Self::Cluster(conn) => conn.get_db(),
+        }
+    }
+}
+
+async fn connect_master(client: &mut SentinelClient, service: &str) -> anyhow::Result<Master> {
+    let master = client
+        .async_get_client()
+        .await
+        .with_context(|| format!("failed to discover redis master for {service}"))?;
+    let addr = master.get_connection_info().addr().clone();
+
+    let manager = ConnectionManager::new(master.clone())
+        .await
+        .with_context(|| format!("failed to connect to redis master {addr}"))?;
+
+    Ok(Master {
+        addr,
+        client: master,
+        manager,
+    })
+}
+
+/// Opens a client for a single node, credentials from the environment take
+/// precedence over the ones in `url`.
+fn node_client(
+    url: &str,
+    username: Option<&str>,
+    password: Option<&str>,
+    certificates: Option<TlsCertificates>,
+) -> anyhow::Result<redis::Client> {
+    let mut info = url
+        .into_connection_info()
+        .context("failed to parse redis url")?;
+
+    let mut auth = info.redis_settings().clone();
+    if let Some(username) = username {
+        auth = auth.set_username(username);
+    }
+    if let Some(password) = password {
+        auth = auth.set_password(password);
+    }
+    info = info.set_redis_settings(auth);
+
+    match certificates {
+        Some(certs) => redis::Client::build_with_tls(info, certs),
+        None => redis::Client::open(info),
+    }
+    .context("failed to create redis client")
+}
+
+/// Splits `[userinfo@]host1,host2[/path]` into its parts.
+fn split_url(rest: &str) -> anyhow::Result<(Option<&str>, Vec<&str>, &str)> {
+    let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+    let (userinfo, hosts) = match authority.rsplit_once('@') {
+        Some((userinfo, hosts)) => (Some(userinfo), hosts),
+        None => (None, authority),
+    };
+
+    let hosts = hosts
+        .split(',')
+        .map(str::trim)
+        .filter(|h| !h.is_empty())
+        .collect::<Vec<_>>();
+
+    if hosts.is_empty() {
+        anyhow::bail!("redis url has no hosts");
+    }
+
+    Ok((userinfo, hosts, path))
+}
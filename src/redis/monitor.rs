@@ -0,0 +1,62 @@
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use std::time::Duration;
+
+use super::RedisClient;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Periodically checks redis, detecting when an outage ends.
+pub struct RedisMonitor {
+    client: RedisClient,
+}
+
+impl RedisMonitor {
+    pub fn new(client: RedisClient) -> Self {
+        Self { client }
+    }
+}
+
+#[async_trait::async_trait]
+impl BackgroundService for RedisMonitor {
+    async fn start(&self, shutdown: ShutdownWatch) {
+        loop {
+            if shutdown.borrow().has_changed() {
+                tracing::info!("stopping redis monitor..");
+                break;
+            }
+
+            self.client.check().await;
+
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        }
+    }
+}
+
+/// Follows sentinel failovers on a schedule of its own, so a slow health
+/// check doesn't delay switching to the new master.
+pub struct FailoverWatcher {
+    client: RedisClient,
+}
+
+impl FailoverWatcher {
+    pub fn new(client: RedisClient) -> Self {
+        Self { client }
+    }
+}
+
+#[async_trait::async_trait]
+impl BackgroundService for FailoverWatcher {
+    async fn start(&self, shutdown: ShutdownWatch) {
+        loop {
+            if shutdown.borrow().has_changed() {
+                tracing::info!("stopping redis failover watcher..");
+                break;
+            }
+
+            self.client.follow_failover().await;
+
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        }
+    }
+}
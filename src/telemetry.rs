@@ -0,0 +1,93 @@
+use anyhow::Context as _;
+use opentelemetry::context::FutureExt;
+use opentelemetry::global::{self, BoxedTracer};
+use opentelemetry::trace::{Status, TraceContextExt, Tracer};
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Sets up span export over OTLP/HTTP when `OTEL_EXPORTER_OTLP_ENDPOINT` or
+/// `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` is set. The exporter reads the other
+/// standard `OTEL_*` variables itself.
+///
+/// Must run outside of a tokio runtime, the exporter uses a blocking client
+/// on its own thread.
+pub fn init() -> anyhow::Result<()> {
+    let configured = [
+        "OTEL_EXPORTER_OTLP_ENDPOINT",
+        "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+    ]
+    .iter()
+    .any(|var| std::env::var(var).is_ok_and(|v| !v.trim().is_empty()));
+
+    if !configured {
+        return Ok(());
+    }
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()
+        .context("failed to create otlp span exporter")?;
+
+    let mut resource = Resource::builder();
+    if std::env::var("OTEL_SERVICE_NAME").is_err() {
+        resource = resource.with_service_name("swarmly");
+    }
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource.build())
+        .build();
+
+    global::set_tracer_provider(provider);
+    ENABLED.store(true, Ordering::Relaxed);
+
+    tracing::info!("exporting spans over otlp");
+
+    Ok(())
+}
+
+/// Whether spans are exported, lets hot paths skip building them.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn tracer() -> BoxedTracer {
+    global::tracer("swarmly")
+}
+
+/// Runs `fut` inside a span, so spans started within it become its children.
+/// The span is marked as failed when `fut` returns an error.
+pub async fn in_span<T, F>(
+    name: &'static str,
+    attributes: Vec<KeyValue>,
+    fut: F,
+) -> anyhow::Result<T>
+where
+    F: Future<Output = anyhow::Result<T>>,
+{
+    if !is_enabled() {
+        return fut.await;
+    }
+
+    let tracer = tracer();
+    let span = tracer
+        .span_builder(name)
+        .with_attributes(attributes)
+        .start(&tracer);
+    let cx = Context::current_with_span(span);
+
+    let result = fut.with_context(cx.clone()).await;
+
+    let span = cx.span();
+    if let Err(err) = &result {
+        span.set_status(Status::error(format!("{err:#}")));
+    }
+    span.end();
+
+    result
+}
@@ -1,28 +1,63 @@
 use anyhow::Context;
+use chrono::{DateTime, Utc};
 use pingora::listeners::TlsAccept;
 use pingora::listeners::tls::TlsSettings;
 use pingora::protocols::tls::TlsRef;
 use pingora::tls::ssl::NameType;
+use serde::Serialize;
+use std::any::Any;
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
 use std::time::Duration;
 use tokio::sync::Mutex;
 
-use self::acme::AcmeResolver;
-use self::storage::TlsStorage;
-use crate::config::provider::ConfigProvider;
+use crate::config::provider::{ConfigProvider, TlsProfile};
+use crate::errors;
+use crate::events::EventBus;
 use crate::redis::RedisClient;
 
 pub use self::acme::service::AcmeChallengeService;
+pub use self::acme::{AcmeResolver, provider_from_label};
+pub use self::cert::Certificate;
+pub use self::client_auth::{ClientAuthPolicy, ClientIdentity};
+pub use self::lock::IssuanceLock;
+pub use self::policy::profile_from_env;
+pub use self::storage::{TlsStorage, data_dir};
 
 mod acme;
 mod cert;
+mod client_auth;
+mod lock;
+mod policy;
 mod storage;
 
 static _DEV_CRT: &[u8] = include_bytes!("../docker/dev.crt");
 static _DEV_KEY: &[u8] = include_bytes!("../docker/dev.key");
 
+/// Handshake details, attached to the TLS digest of every connection.
+pub struct HandshakeInfo {
+    pub sni: Option<String>,
+    /// Verified client certificate, if one was presented.
+    pub client: Option<ClientIdentity>,
+    /// Set once the handshake was exported as a span.
+    pub traced: AtomicBool,
+}
+
+#[derive(Serialize)]
+pub struct CertificateStatus {
+    pub domain: String,
+    pub issued_at: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub needs_renewal: bool,
+    /// Why the certificate couldn't be read.
+    pub error: Option<String>,
+}
+
 pub struct TlsResolver<P> {
     inner: Arc<Mutex<TlsResolverInner<P>>>,
+    profile: Option<TlsProfile>,
 }
 
 struct TlsResolverInner<P> {
@@ -31,14 +66,17 @@ struct TlsResolverInner<P> {
     service: AcmeChallengeService,
     provider: P,
     redis: Option<RedisClient>,
-    node_id: String,
+    client_auth: HashMap<String, ClientAuthPolicy>,
+    profiles: HashMap<String, TlsProfile>,
+    /// Routed domains with their preferred acme provider.
+    domains: Vec<(String, Option<String>)>,
 }
 
 impl<P: ConfigProvider + Send + Sync + 'static> TlsResolver<P> {
     pub async fn new(
         provider: P,
         service: AcmeChallengeService,
-        redis: Option<RedisClient>,
+        redis: Option<(RedisClient, EventBus)>,
     ) -> anyhow::Result<Option<Self>> {
         let acme_resolver =
             match AcmeResolver::from_env().context("failed to create acme resolver from env")? {
@@ -46,11 +84,19 @@ impl<P: ConfigProvider + Send + Sync + 'static> TlsResolver<P> {
                 None => return Ok(None),
             };
 
+        let profile = profile_from_env()?;
+
+        let events = redis.as_ref().map(|(_, events)| events.clone());
         let inner = TlsResolverInner::new(provider, service, acme_resolver, redis).await?;
 
         let inner = Arc::new(Mutex::new(inner));
-        let instance = Self { inner };
+        let instance = Self { inner, profile };
         instance.connect_config_callback().await;
+        instance.connect_recover_callback().await;
+
+        if let Some(events) = events {
+            instance.connect_event_callback(&events);
+        }
 
         Ok(Some(instance))
     }
@@ -62,6 +108,10 @@ impl<P: ConfigProvider + Send + Sync + 'static> TlsResolver<P> {
 
         settings.enable_h2();
 
+        if let Some(profile) = &self.profile {
+            policy::apply_global(&mut settings, profile).expect("failed to apply tls profile");
+        }
+
         settings
     }
 
@@ -76,32 +126,146 @@ impl<P: ConfigProvider + Send + Sync + 'static> TlsResolver<P> {
                 let inner = inner.clone();
 
                 async move {
-                    let domains = value.into_iter().map(|(d, _)| d);
-                    let mut inner = inner.lock().await;
-
-                    for domain in domains {
-                        let needs_renewal = inner.storage.needs_renewal(&domain).await;
-                        match needs_renewal {
-                            Ok(false) => continue,
-                            Ok(true) => {
-                                tracing::info!("issuing/renewing cert for domain: {}", domain);
-                                if let Err(err) = inner.issue_and_store_cert(&domain).await {
+                    let mut guard = inner.lock().await;
+
+                    guard.client_auth = value
+                        .iter()
+                        .filter_map(|(domain, config)| {
+                            let client_auth = config.options.client_auth.as_ref()?;
+                            match ClientAuthPolicy::load(client_auth) {
+                                Ok(policy) => Some((domain.clone(), policy)),
+                                Err(err) => {
                                     tracing::error!(
-                                        "failed to issue cert for domain({}): {err:?}",
-                                        domain
-                                    )
+                                        "failed to load client auth for domain({domain}): {err:?}"
+                                    );
+                                    None
                                 }
                             }
-                            Err(err) => {
-                                tracing::error!(
-                                    "failed to check renewal for domain({domain}): {err:?}"
-                                );
-                            }
-                        }
-                    }
+                        })
+                        .collect();
+
+                    guard.profiles = value
+                        .iter()
+                        .filter_map(|(domain, config)| {
+                            let profile = config.options.tls_profile.clone()?;
+                            Some((domain.clone(), profile))
+                        })
+                        .collect();
+
+                    guard.domains = value
+                        .into_iter()
+                        .map(|(domain, config)| (domain, config.options.acme_provider))
+                        .collect();
+                    drop(guard);
+
+                    TlsResolverInner::renew_all(&inner).await;
                 }
             });
     }
+
+    /// Once redis is back, drops certificates read during the outage and
+    /// catches up on the issuance that was paused.
+    async fn connect_recover_callback(&self) {
+        let Some(redis) = self.inner.lock().await.redis.clone() else {
+            return;
+        };
+
+        let inner = self.inner.clone();
+
+        redis.set_recover_callback(move || {
+            let inner = inner.clone();
+
+            async move {
+                inner.lock().await.storage.clear_cache();
+                TlsResolverInner::renew_all(&inner).await;
+            }
+        });
+    }
+
+    /// Drops certificates another node replaced, deleted or revoked.
+    fn connect_event_callback(&self, events: &EventBus) {
+        let inner = self.inner.clone();
+
+        events.set_event_callback(move |event| {
+            let inner = inner.clone();
+
+            async move {
+                inner.lock().await.storage.apply_event(&event).await;
+            }
+        });
+    }
+
+    /// Certificates of every routed domain, for the admin api.
+    pub async fn certificates(&self) -> Vec<CertificateStatus> {
+        // read through a handle of its own, handshakes don't wait on storage round trips
+        let (mut domains, mut storage) = {
+            let inner = self.inner.lock().await;
+            let domains: Vec<_> = inner.domains.iter().map(|(d, _)| d.clone()).collect();
+            (domains, inner.storage.detached())
+        };
+        domains.sort();
+
+        let mut certificates = Vec::new();
+        for domain in domains {
+            let status = match storage.fetch_from_backend(&domain).await {
+                Ok(Some(cert)) => CertificateStatus {
+                    issued_at: DateTime::from_timestamp(cert.order_timestamp() as i64, 0),
+                    expires_at: cert
+                        .expires_at()
+                        .and_then(|at| DateTime::from_timestamp(at, 0)),
+                    needs_renewal: cert.is_expiring(),
+                    error: None,
+                    domain,
+                },
+                Ok(None) => CertificateStatus {
+                    issued_at: None,
+                    expires_at: None,
+                    needs_renewal: true,
+                    error: None,
+                    domain,
+                },
+                Err(err) => CertificateStatus {
+                    issued_at: None,
+                    expires_at: None,
+                    needs_renewal: false,
+                    error: Some(format!("{err:#}")),
+                    domain,
+                },
+            };
+            certificates.push(status);
+        }
+
+        certificates
+    }
+
+    pub async fn is_routed(&self, domain: &str) -> bool {
+        self.inner
+            .lock()
+            .await
+            .domains
+            .iter()
+            .any(|(d, _)| d == domain)
+    }
+
+    /// Issues a new certificate for `domain`, even if the current one is still valid.
+    pub async fn renew(&self, domain: &str) -> anyhow::Result<()> {
+        let provider = self
+            .inner
+            .lock()
+            .await
+            .domains
+            .iter()
+            .find(|(d, _)| d == domain)
+            .map(|(_, provider)| provider.clone())
+            .with_context(|| format!("{domain} is not routed"))?;
+
+        let result =
+            TlsResolverInner::issue_and_store_cert(&self.inner, domain, provider.as_deref()).await;
+        if let Err(err) = &result {
+            errors::record("acme", Some(domain), err);
+        }
+        result
+    }
 }
 
 impl<P: ConfigProvider + Send + Sync + 'static> TlsResolverInner<P> {
@@ -113,11 +277,10 @@ impl<P: ConfigProvider + Send + Sync + 'static> TlsResolverInner<P> {
         provider: P,
         service: AcmeChallengeService,
         acme_resolver: AcmeResolver,
-        redis: Option<RedisClient>,
+        redis: Option<(RedisClient, EventBus)>,
     ) -> anyhow::Result<Self> {
-        let storage = TlsStorage::from_env(redis.clone());
-
-        let node_id = std::env::var("HOSTNAME").unwrap_or_else(|_| "unknown".to_owned());
+        let storage = TlsStorage::from_env(redis.clone())?;
+        let redis = redis.map(|(client, _)| client);
 
         Ok(Self {
             storage,
@@ -125,78 +288,133 @@ impl<P: ConfigProvider + Send + Sync + 'static> TlsResolverInner<P> {
             acme_resolver,
             provider,
             redis,
-            node_id,
+            client_auth: HashMap::new(),
+            profiles: HashMap::new(),
+            domains: Vec::new(),
         })
     }
 
-    pub async fn issue_and_store_cert(&mut self, domain: &str) -> anyhow::Result<()> {
-        if let Some(redis) = &self.redis {
-            self.issue_with_lock(domain, redis.clone()).await
-        } else {
-            let cert = self
-                .acme_resolver
-                .issue_cert(domain, &self.service)
-                .await
-                .with_context(|| format!("failed to issue cert for {domain}"))?;
-            self.storage.set(domain, cert).await
+    /// Issues every missing or expiring cert. The resolver is only locked
+    /// while checking and issuing, not while waiting for another node.
+    async fn renew_all(inner: &Mutex<Self>) {
+        let domains = inner.lock().await.domains.clone();
+
+        for (domain, provider) in domains {
+            let mut guard = inner.lock().await;
+            if guard.redis.as_ref().is_some_and(|r| !r.is_healthy()) {
+                tracing::warn!("redis is unreachable, certificate issuance is paused");
+                return;
+            }
+
+            match guard.storage.needs_renewal(&domain).await {
+                Ok(false) => continue,
+                Ok(true) => {
+                    drop(guard);
+                    tracing::info!("issuing/renewing cert for domain: {}", domain);
+                    if let Err(err) =
+                        Self::issue_and_store_cert(inner, &domain, provider.as_deref()).await
+                    {
+                        tracing::error!("failed to issue cert for domain({}): {err:?}", domain);
+                        errors::record("acme", Some(&domain), &err);
+                    }
+                }
+                Err(err) => {
+                    tracing::error!("failed to check renewal for domain({domain}): {err:?}");
+                }
+            }
         }
     }
 
-    async fn issue_with_lock(&mut self, domain: &str, redis: RedisClient) -> anyhow::Result<()> {
-        const LOCK_TTL_SECS: u64 = 300;
+    /// Issues a cert for `domain` under the issuance lock, or waits for the
+    /// node holding it to store one.
+    pub async fn issue_and_store_cert(
+        inner: &Mutex<Self>,
+        domain: &str,
+        provider: Option<&str>,
+    ) -> anyhow::Result<()> {
         const POLL_INTERVAL: Duration = Duration::from_secs(5);
         const MAX_POLLS: u32 = 60;
 
-        let lock_key = format!("swarmly:lock:{}", domain);
+        // handshakes only wait on the resolver while this node issues
+        let mut storage = match inner.lock().await.try_issue(domain, provider).await? {
+            Some(storage) => storage,
+            None => return Ok(()),
+        };
+
+        tracing::info!(
+            "another node is issuing cert for {}, waiting up to {}s",
+            domain,
+            IssuanceLock::TTL_SECS
+        );
+
+        let old_timestamp = storage
+            .fetch_from_backend(domain)
+            .await?
+            .map(|c| c.order_timestamp());
+
+        for attempt in 1..=MAX_POLLS {
+            tokio::time::sleep(POLL_INTERVAL).await;
+
+            match storage.fetch_from_backend(domain).await {
+                Ok(Some(cert)) if Some(cert.order_timestamp()) != old_timestamp => {
+                    tracing::info!("cert for {} available after {} polls", domain, attempt);
+                    inner
+                        .lock()
+                        .await
+                        .storage
+                        .fetch_from_backend(domain)
+                        .await?;
+                    return Ok(());
+                }
+                Ok(_) => continue,
+                Err(err) => tracing::warn!("error polling for cert {domain}: {err:?}"),
+            }
+        }
 
-        let acquired = redis
-            .set_nx(&lock_key, self.node_id.as_bytes().to_vec(), LOCK_TTL_SECS)
-            .await
-            .context("failed to acquire cert issuance lock")?;
+        anyhow::bail!("timeout waiting for cert for domain {domain} from another node")
+    }
 
-        if acquired {
-            tracing::info!(
+    /// Issues and stores a cert if the issuance lock is free. Otherwise
+    /// returns a storage handle to wait for the lock holder's cert on.
+    async fn try_issue(
+        &mut self,
+        domain: &str,
+        provider: Option<&str>,
+    ) -> anyhow::Result<Option<TlsStorage>> {
+        let lock_dir = PathBuf::from(data_dir()).join("locks");
+        let lock = IssuanceLock::acquire(self.redis.as_ref(), &lock_dir, domain).await?;
+
+        let Some(lock) = lock else {
+            return Ok(Some(self.storage.detached()));
+        };
+
+        match lock.token() {
+            Some(token) => tracing::info!(
+                "node {} acquired cert lock for {} (fencing token {}), issuing",
+                IssuanceLock::holder_id(),
+                domain,
+                token
+            ),
+            None => tracing::info!(
                 "node {} acquired cert lock for {}, issuing",
-                self.node_id,
+                IssuanceLock::holder_id(),
                 domain
-            );
-
-            let result = self.acme_resolver.issue_cert(domain, &self.service).await;
+            ),
+        }
 
-            if let Err(err) = redis.del(&lock_key).await {
-                tracing::warn!("failed to release cert lock for {domain}: {err:?}");
-            }
+        let result = self
+            .acme_resolver
+            .issue_cert(domain, provider, &self.service)
+            .await;
 
-            let cert = result.with_context(|| format!("failed to issue cert for {domain}"))?;
-            self.storage.set(domain, cert).await
-        } else {
-            tracing::info!(
-                "another node is issuing cert for {}, waiting up to {}s",
-                domain,
-                LOCK_TTL_SECS
-            );
-
-            let old_timestamp = self
-                .storage
-                .fetch_from_backend(domain)
-                .await?
-                .map(|c| c.order_timestamp());
-
-            for attempt in 1..=MAX_POLLS {
-                tokio::time::sleep(POLL_INTERVAL).await;
-
-                match self.storage.fetch_from_backend(domain).await {
-                    Ok(Some(cert)) if Some(cert.order_timestamp()) != old_timestamp => {
-                        tracing::info!("cert for {} available after {} polls", domain, attempt);
-                        return Ok(());
-                    }
-                    Ok(_) => continue,
-                    Err(err) => tracing::warn!("error polling for cert {domain}: {err:?}"),
-                }
-            }
+        // stored before release, so a node waiting on the lock finds the cert
+        let stored = match result {
+            Ok(cert) => self.storage.set(domain, cert, lock.token()).await,
+            Err(err) => Err(err.context(format!("failed to issue cert for {domain}"))),
+        };
+        lock.release().await;
 
-            anyhow::bail!("timeout waiting for cert for domain {domain} from another node")
-        }
+        stored.map(|()| None)
     }
 }
 
@@ -206,6 +424,20 @@ impl<P: ConfigProvider + Send + Sync> TlsAccept for TlsResolver<P> {
         if let Some(domain) = ssl.servername(NameType::HOST_NAME).map(str::to_owned) {
             let mut inner = self.inner.lock().await;
 
+            if let Some(profile) = inner.profiles.get(&domain)
+                && let Err(err) = policy::apply_domain(ssl, profile)
+            {
+                tracing::debug!("refusing tls handshake for {domain}: {err:?}");
+                return;
+            }
+
+            if let Some(policy) = inner.client_auth.get(&domain)
+                && let Err(err) = policy.apply(ssl)
+            {
+                tracing::error!("failed to set client auth for {domain}: {err:?}");
+                return;
+            }
+
             let cert = match inner.storage.get(&domain).await {
                 Ok(Some(cert)) => cert,
                 Ok(None) => return,
@@ -222,17 +454,37 @@ impl<P: ConfigProvider + Send + Sync> TlsAccept for TlsResolver<P> {
                 tracing::error!("failed to set certificate for {domain}: {err:?}");
                 return;
             }
+            for intermediate in cert.chain() {
+                if let Err(err) = ssl.add_chain_cert(intermediate.clone()) {
+                    tracing::error!("failed to add chain cert for {domain}: {err:?}");
+                    return;
+                }
+            }
             if let Err(err) = ssl.set_private_key(key) {
                 tracing::error!("failed to set private key for {domain}: {err:?}");
             }
         }
     }
+
+    async fn handshake_complete_callback(
+        &self,
+        ssl: &TlsRef,
+    ) -> Option<Arc<dyn Any + Send + Sync>> {
+        let info = HandshakeInfo {
+            sni: ssl.servername(NameType::HOST_NAME).map(str::to_owned),
+            client: ClientIdentity::from_ssl(ssl),
+            traced: AtomicBool::new(false),
+        };
+
+        Some(Arc::new(info))
+    }
 }
 
 impl<P: ConfigProvider> Clone for TlsResolver<P> {
     fn clone(&self) -> Self {
         Self {
             inner: Arc::clone(&self.inner),
+            profile: self.profile.clone(),
         }
     }
 }
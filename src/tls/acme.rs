@@ -1,11 +1,15 @@
 use anyhow::Context;
 use instant_acme::{
-    Account, ChallengeType, Identifier, LetsEncrypt, NewAccount, NewOrder, RetryPolicy,
+    Account, ChallengeType, ExternalAccountKey, Identifier, LetsEncrypt, NewAccount, NewOrder,
+    RetryPolicy, ZeroSsl,
 };
-use std::sync::OnceLock;
+use opentelemetry::KeyValue;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use super::cert::Certificate;
+use crate::{metrics, telemetry};
 
 pub mod service;
 
@@ -13,38 +17,218 @@ use service::AcmeChallengeService;
 
 #[derive(Clone)]
 pub struct AcmeResolver {
-    account: OnceLock<Account>,
-    contact: Option<String>,
+    directories: Vec<Arc<AcmeDirectory>>,
+    failures: Arc<Mutex<HashMap<String, u32>>>,
+    fallback_after: u32,
+}
+
+struct AcmeDirectory {
+    name: String,
     url: String,
+    contact: Option<String>,
+    eab: Option<ExternalAccountKey>,
+    account: OnceLock<Account>,
 }
 
 impl AcmeResolver {
+    const DEFAULT_FALLBACK_AFTER: u32 = 3;
+
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let primary = match AcmeDirectory::from_env("ACME_PROVIDER", "ACME_EAB")? {
+            Some(directory) => directory,
+            None => return Ok(None),
+        };
+
+        let mut directories = vec![Arc::new(primary)];
+
+        if let Some(fallback) =
+            AcmeDirectory::from_env("ACME_FALLBACK_PROVIDER", "ACME_FALLBACK_EAB")?
+        {
+            tracing::info!(
+                "acme fallback provider configured: {} ({})",
+                fallback.name,
+                fallback.url
+            );
+            directories.push(Arc::new(fallback));
+        }
+
+        let fallback_after = match std::env::var("ACME_FALLBACK_AFTER") {
+            Ok(v) => v
+                .trim()
+                .parse()
+                .with_context(|| format!("failed to parse ACME_FALLBACK_AFTER {v} as u32"))?,
+            Err(_) => Self::DEFAULT_FALLBACK_AFTER,
+        };
+
+        Ok(Some(Self {
+            directories,
+            failures: Arc::new(Mutex::new(HashMap::new())),
+            fallback_after,
+        }))
+    }
+
+    /// Configured directories, with the one named by `preferred` moved to the front.
+    fn directories_for(&self, domain: &str, preferred: Option<&str>) -> Vec<Arc<AcmeDirectory>> {
+        let mut directories = self.directories.clone();
+
+        if let Some(preferred) = preferred {
+            let url = directory_url(preferred);
+            match directories
+                .iter()
+                .position(|d| Some(&d.url) == url.as_ref())
+            {
+                Some(pos) => directories[..=pos].rotate_right(1),
+                None => tracing::warn!(
+                    "acme provider {preferred} requested by {domain} is not configured, using default"
+                ),
+            }
+        }
+
+        directories
+    }
+
+    pub async fn issue_cert(
+        &self,
+        domain: &str,
+        preferred: Option<&str>,
+        service: &AcmeChallengeService,
+    ) -> anyhow::Result<Certificate> {
+        let attributes = vec![KeyValue::new("swarmly.domain", domain.to_owned())];
+
+        telemetry::in_span(
+            "acme issue cert",
+            attributes,
+            self.issue_with_fallback(domain, preferred, service),
+        )
+        .await
+    }
+
+    async fn issue_with_fallback(
+        &self,
+        domain: &str,
+        preferred: Option<&str>,
+        service: &AcmeChallengeService,
+    ) -> anyhow::Result<Certificate> {
+        let directories = self.directories_for(domain, preferred);
+
+        let err = match directories[0].issue_cert(domain, service).await {
+            Ok(cert) => {
+                record_issuance(&directories[0].name, true);
+                self.failures.lock().unwrap().remove(domain);
+                return Ok(cert);
+            }
+            Err(err) => {
+                record_issuance(&directories[0].name, false);
+                err
+            }
+        };
+
+        let failures = {
+            let mut failures = self.failures.lock().unwrap();
+            let count = failures.entry(domain.to_owned()).or_default();
+            *count += 1;
+            *count
+        };
+
+        let fallback = match directories.get(1) {
+            Some(fallback) if failures >= self.fallback_after => fallback,
+            _ => return Err(err),
+        };
+
+        tracing::warn!(
+            "acme provider {} failed {} times for {}, falling back to {}: {err:?}",
+            directories[0].name,
+            failures,
+            domain,
+            fallback.name
+        );
+
+        let cert = fallback.issue_cert(domain, service).await;
+        record_issuance(&fallback.name, cert.is_ok());
+        let cert = cert.with_context(|| format!("fallback provider {} failed", fallback.name))?;
+
+        self.failures.lock().unwrap().remove(domain);
+
+        Ok(cert)
+    }
+}
+
+/// Directory url of a provider name, alias or url, `None` for unknown names.
+fn directory_url(provider: &str) -> Option<String> {
+    let provider = provider.trim().to_lowercase();
+
+    let url = match provider.as_str() {
+        "letsencrypt" | "le" => LetsEncrypt::Production.url(),
+        "staging-letsencrypt" | "sle" => LetsEncrypt::Staging.url(),
+        "zerossl" => ZeroSsl::Production.url(),
+        "google" | "gts" => "https://dv.acme-v02.api.pki.goog/directory",
+        "staging-google" | "sgts" => "https://dv.acme-v02.test-api.pki.goog/directory",
+        url if url.starts_with("https://") || url.starts_with("http://") => url,
+        _ => return None,
+    };
+
+    Some(url.to_owned())
+}
+
+/// Parses `swarmly.acme.provider` into a directory url. Unknown names, and
+/// providers that are neither `ACME_PROVIDER` nor `ACME_FALLBACK_PROVIDER`
+/// while acme is configured, are rejected.
+pub fn provider_from_label(value: &str) -> anyhow::Result<String> {
+    let url = directory_url(value).with_context(|| format!("unknown acme provider {value}"))?;
+
+    let configured: Vec<_> = ["ACME_PROVIDER", "ACME_FALLBACK_PROVIDER"]
+        .into_iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .filter_map(|provider| directory_url(&provider))
+        .collect();
+
+    if !configured.is_empty() && !configured.contains(&url) {
+        anyhow::bail!(
+            "acme provider {value} is not configured, set it as ACME_PROVIDER or ACME_FALLBACK_PROVIDER"
+        );
+    }
+
+    Ok(url)
+}
+
+impl AcmeDirectory {
     const CHALLENGE_RETRY_POLICY: RetryPolicy = RetryPolicy::new()
         .initial_delay(Duration::from_millis(500))
         .timeout(Duration::from_secs(60));
 
-    pub fn from_env() -> anyhow::Result<Option<Self>> {
-        let provider = match std::env::var("ACME_PROVIDER") {
+    fn from_env(provider_var: &str, eab_prefix: &str) -> anyhow::Result<Option<Self>> {
+        let name = match std::env::var(provider_var) {
             Ok(p) => p.trim().to_lowercase(),
             _ => return Ok(None),
         };
 
-        let url = match provider.as_ref() {
-            "letsencrypt" | "le" => LetsEncrypt::Production.url(),
-            "staging-letsencrypt" | "sle" => LetsEncrypt::Staging.url(),
-            other => other,
-        }
-        .to_owned();
+        let url = directory_url(&name).with_context(|| {
+            format!("unknown {provider_var} {name}, expected a provider name or directory url")
+        })?;
+
+        let kid = std::env::var(format!("{eab_prefix}_KID")).ok();
+        let hmac = std::env::var(format!("{eab_prefix}_HMAC")).ok();
+
+        let eab = match (kid, hmac) {
+            (Some(kid), Some(hmac)) => {
+                let key = decode_base64url(hmac.trim())
+                    .with_context(|| format!("failed to decode {eab_prefix}_HMAC as base64url"))?;
+                Some(ExternalAccountKey::new(kid.trim().to_owned(), &key))
+            }
+            (None, None) => None,
+            _ => anyhow::bail!("both {eab_prefix}_KID and {eab_prefix}_HMAC must be set"),
+        };
 
         let contact = std::env::var("ACME_CONTACT").ok();
 
         Ok(Some(Self {
-            account: OnceLock::new(),
-            contact,
+            name,
             url,
+            contact,
+            eab,
+            account: OnceLock::new(),
         }))
     }
-
     async fn account(&self) -> anyhow::Result<&Account> {
         if let Some(account) = self.account.get() {
             return Ok(account);
@@ -69,7 +253,7 @@ impl AcmeResolver {
                     only_return_existing: false,
                 },
                 self.url.clone(),
-                None,
+                self.eab.as_ref(),
             )
             .await
             .context("failed to create acme account")?;
@@ -79,17 +263,14 @@ impl AcmeResolver {
         Ok(self.account.get().unwrap())
     }
 
-    pub async fn issue_cert<D>(
+    async fn issue_cert(
         &self,
-        domain: D,
+        domain: &str,
         service: &AcmeChallengeService,
-    ) -> anyhow::Result<Certificate>
-    where
-        D: Clone + Into<String> + std::fmt::Display,
-    {
-        tracing::debug!("ordering cert for domain: {}", domain);
+    ) -> anyhow::Result<Certificate> {
+        tracing::debug!("ordering cert for domain {} from {}", domain, self.name);
 
-        let identifiers = [Identifier::Dns(domain.clone().into())];
+        let identifiers = [Identifier::Dns(domain.to_owned())];
         let mut order = self
             .account()
             .await?
@@ -146,3 +327,18 @@ impl AcmeResolver {
             .context("failed to create certificate")
     }
 }
+
+fn decode_base64url(value: &str) -> anyhow::Result<Vec<u8>> {
+    let mut value = value.replace('-', "+").replace('_', "/");
+    while !value.len().is_multiple_of(4) {
+        value.push('=');
+    }
+    openssl::base64::decode_block(&value).context("invalid base64")
+}
+
+fn record_issuance(provider: &str, success: bool) {
+    let result = if success { "success" } else { "failure" };
+    metrics::ACME_ISSUANCE
+        .with_label_values(&[provider, result])
+        .inc();
+}
@@ -1,19 +1,21 @@
+use anyhow::Context;
 use http::Response;
 use pingora::apps::http_app::ServeHttp;
 use pingora::protocols::http::ServerSession;
-use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::RwLock;
+use std::time::{Duration, SystemTime};
 
 use crate::redis::RedisClient;
+use crate::tls::storage::data_dir;
 
 const CHALLENGE_KEY_PREFIX: &str = "swarmly:challenge:";
 const CHALLENGE_TTL_SECS: u64 = 60;
-const MEMORY_CHALLENGE_LIFETIME: Duration = Duration::from_secs(60);
+const FILE_CHALLENGE_LIFETIME: Duration = Duration::from_secs(60);
 
 enum ChallengeBackend {
-    Memory(Arc<RwLock<HashMap<String, String>>>),
+    /// `DATA_DIR/challenges`, so any replica sharing the volume can answer.
+    Filesystem(PathBuf),
     Redis(RedisClient),
 }
 
@@ -26,7 +28,7 @@ impl AcmeChallengeService {
     pub fn new(redis: Option<RedisClient>) -> Self {
         let backend = match redis {
             Some(client) => ChallengeBackend::Redis(client),
-            None => ChallengeBackend::Memory(Arc::new(RwLock::new(HashMap::new()))),
+            None => ChallengeBackend::Filesystem(PathBuf::from(data_dir()).join("challenges")),
         };
         Self {
             backend: Arc::new(backend),
@@ -41,16 +43,22 @@ impl AcmeChallengeService {
                     .set(&key, proof.as_bytes().to_vec(), CHALLENGE_TTL_SECS)
                     .await
             }
-            ChallengeBackend::Memory(map) => {
-                let token = token.to_owned();
-                let proof = proof.to_owned();
-                let map_clone = Arc::clone(map);
+            ChallengeBackend::Filesystem(dir) => {
+                if !is_valid_token(token) {
+                    anyhow::bail!("invalid acme challenge token {token}");
+                }
 
-                map.write().await.insert(token.clone(), proof);
+                let path = dir.join(token);
+                tokio::fs::create_dir_all(dir)
+                    .await
+                    .context("failed to create challenges directory")?;
+                tokio::fs::write(&path, proof)
+                    .await
+                    .context("failed to save acme challenge")?;
 
                 tokio::spawn(async move {
-                    tokio::time::sleep(MEMORY_CHALLENGE_LIFETIME).await;
-                    map_clone.write().await.remove(&token);
+                    tokio::time::sleep(FILE_CHALLENGE_LIFETIME).await;
+                    let _ = tokio::fs::remove_file(&path).await;
                 });
 
                 Ok(())
@@ -72,7 +80,7 @@ impl ServeHttp for AcmeChallengeService {
         let path = session.req_header().uri.path();
 
         let token = match path.strip_prefix("/.well-known/acme-challenge/") {
-            Some(t) if !t.is_empty() => t,
+            Some(t) if is_valid_token(t) => t,
             _ => return not_found(),
         };
 
@@ -88,14 +96,37 @@ impl ServeHttp for AcmeChallengeService {
                     }
                 }
             }
-            ChallengeBackend::Memory(map) => match map.read().await.get(token) {
-                Some(proof) => Response::new(proof.as_bytes().to_vec()),
-                None => not_found(),
-            },
+            ChallengeBackend::Filesystem(dir) => {
+                let path = dir.join(token);
+                let fresh = tokio::fs::metadata(&path)
+                    .await
+                    .ok()
+                    .and_then(|meta| meta.modified().ok())
+                    .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                    .is_some_and(|age| age <= FILE_CHALLENGE_LIFETIME);
+
+                if !fresh {
+                    return not_found();
+                }
+
+                match tokio::fs::read(&path).await {
+                    Ok(proof) => Response::new(proof),
+                    Err(_) => not_found(),
+                }
+            }
         }
     }
 }
 
+/// ACME tokens are base64url, anything else can't be a token and must not
+/// reach the filesystem.
+fn is_valid_token(token: &str) -> bool {
+    !token.is_empty()
+        && token
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
 fn challenge_key(token: &str) -> String {
     format!("{}{}", CHALLENGE_KEY_PREFIX, token)
 }
@@ -1,12 +1,14 @@
 use std::io::{BufWriter, Write};
 
 use anyhow::Context;
+use openssl::asn1::Asn1Time;
 use pingora::tls::pkey::{PKey, Private};
 use pingora::tls::x509::X509;
 
 pub struct Certificate {
     private_key: PKey<Private>,
     certificate: X509,
+    chain: Vec<X509>,
     order_timestamp: u64,
 }
 
@@ -15,11 +17,18 @@ impl Certificate {
         let private_key =
             PKey::private_key_from_pem(pkey).context("failed to parse private key as pem")?;
 
-        let certificate = X509::from_pem(cert).context("failed to parse cert as pem")?;
+        let mut chain = X509::stack_from_pem(cert).context("failed to parse cert as pem")?;
+
+        if chain.is_empty() {
+            anyhow::bail!("pem does not contain any certificate");
+        }
+
+        let certificate = chain.remove(0);
 
         Ok(Self {
             private_key,
             certificate,
+            chain,
             order_timestamp: timestamp,
         })
     }
@@ -36,11 +45,41 @@ impl Certificate {
         &self.certificate
     }
 
+    /// Intermediate certificates, leaf excluded.
+    pub fn chain(&self) -> &[X509] {
+        &self.chain
+    }
+
+    pub fn private_key_pem(&self) -> anyhow::Result<Vec<u8>> {
+        self.private_key
+            .private_key_to_pem_pkcs8()
+            .context("failed to encode private key as pem")
+    }
+
+    /// Leaf certificate followed by the intermediates.
+    pub fn chain_pem(&self) -> anyhow::Result<Vec<u8>> {
+        let mut pem = self
+            .certificate
+            .to_pem()
+            .context("failed to encode cert as pem")?;
+        for intermediate in self.chain.iter() {
+            pem.extend(
+                intermediate
+                    .to_pem()
+                    .context("failed to encode chain as pem")?,
+            );
+        }
+        Ok(pem)
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut buf = BufWriter::new(Vec::new());
 
         let pkey = self.private_key.private_key_to_pem_pkcs8().unwrap();
-        let cert = self.certificate.to_pem().unwrap();
+        let mut cert = self.certificate.to_pem().unwrap();
+        for intermediate in self.chain.iter() {
+            cert.extend(intermediate.to_pem().unwrap());
+        }
 
         buf.write_all(&self.order_timestamp.to_le_bytes()).unwrap();
         buf.write_all(&(pkey.len() as u64).to_le_bytes()).unwrap();
@@ -51,13 +90,40 @@ impl Certificate {
         buf.into_inner().expect("we use simply vector")
     }
 
+    /// `notAfter` of the leaf certificate as a unix timestamp.
+    pub fn expires_at(&self) -> Option<i64> {
+        let epoch = Asn1Time::from_unix(0).ok()?;
+        let diff = epoch.diff(self.certificate.not_after()).ok()?;
+        Some(diff.days as i64 * 86400 + diff.secs as i64)
+    }
+
+    /// `notBefore` of the leaf certificate as a unix timestamp.
+    pub fn not_before(&self) -> Option<i64> {
+        let epoch = Asn1Time::from_unix(0).ok()?;
+        let diff = epoch.diff(self.certificate.not_before()).ok()?;
+        Some(diff.days as i64 * 86400 + diff.secs as i64)
+    }
+
+    /// Whether the certificate is due for renewal, once less than 30 days or
+    /// a third of its validity are left, whichever is shorter. A 90 day
+    /// certificate is renewed after 60 days, and an imported one isn't
+    /// replaced long before it expires.
     pub fn is_expiring(&self) -> bool {
-        const RENEWAL_AFTER_SECS: u64 = 60 * 24 * 3600;
         let now = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
-            .map(|d| d.as_secs())
+            .map(|d| d.as_secs() as i64)
             .unwrap_or(0);
-        now.saturating_sub(self.order_timestamp) >= RENEWAL_AFTER_SECS
+        self.is_expiring_at(now)
+    }
+
+    fn is_expiring_at(&self, now: i64) -> bool {
+        const RENEWAL_BEFORE_SECS: i64 = 30 * 24 * 3600;
+
+        let (Some(not_before), Some(not_after)) = (self.not_before(), self.expires_at()) else {
+            return true;
+        };
+        let renew_before = RENEWAL_BEFORE_SECS.min((not_after - not_before) / 3);
+        now >= not_after - renew_before
     }
 
     pub fn from_bytes(buf: &[u8]) -> anyhow::Result<Self> {
@@ -87,3 +153,59 @@ impl Certificate {
         Self::new(pkey, cert, timestamp)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::x509::X509Builder;
+
+    const DAY: i64 = 24 * 3600;
+    const ISSUED: i64 = 1_700_000_000;
+
+    fn valid_for(days: i64) -> Certificate {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::from_unix(ISSUED).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::from_unix(ISSUED + days * DAY).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        Certificate::new(
+            &key.private_key_to_pem_pkcs8().unwrap(),
+            &builder.build().to_pem().unwrap(),
+            ISSUED as u64,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn renews_90_day_certs_after_60_days() {
+        let cert = valid_for(90);
+        assert!(!cert.is_expiring_at(ISSUED + 59 * DAY));
+        assert!(cert.is_expiring_at(ISSUED + 60 * DAY));
+    }
+
+    #[test]
+    fn renews_imported_certs_close_to_not_after() {
+        let cert = valid_for(365);
+        assert!(!cert.is_expiring_at(ISSUED + 60 * DAY));
+        assert!(!cert.is_expiring_at(ISSUED + 334 * DAY));
+        assert!(cert.is_expiring_at(ISSUED + 335 * DAY));
+    }
+
+    #[test]
+    fn renews_short_lived_certs_after_two_thirds() {
+        let cert = valid_for(6);
+        assert!(!cert.is_expiring_at(ISSUED + 3 * DAY));
+        assert!(cert.is_expiring_at(ISSUED + 4 * DAY));
+    }
+}
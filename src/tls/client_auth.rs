@@ -0,0 +1,120 @@
+use anyhow::Context;
+use openssl::stack::Stack;
+use pingora::protocols::tls::TlsRef;
+use pingora::tls::ssl::SslVerifyMode;
+use pingora::tls::x509::store::X509StoreBuilder;
+use pingora::tls::x509::{X509, X509NameRef, X509VerifyResult};
+use std::net::IpAddr;
+
+use crate::config::provider::{ClientAuth, ClientAuthMode};
+
+/// Client certificate requirements of a single domain, with its CA bundle loaded.
+pub struct ClientAuthPolicy {
+    mode: ClientAuthMode,
+    cas: Vec<X509>,
+}
+
+/// Verified client certificate of a connection.
+pub struct ClientIdentity {
+    pub subject: String,
+    pub sans: Vec<String>,
+}
+
+impl ClientAuthPolicy {
+    pub fn load(config: &ClientAuth) -> anyhow::Result<Self> {
+        let pem = std::fs::read(&config.ca_path)
+            .with_context(|| format!("failed to read client ca {}", config.ca_path.display()))?;
+        let cas = X509::stack_from_pem(&pem).context("failed to parse client ca as pem")?;
+
+        if cas.is_empty() {
+            anyhow::bail!("client ca {} is empty", config.ca_path.display());
+        }
+
+        Ok(Self {
+            mode: config.mode,
+            cas,
+        })
+    }
+
+    pub fn apply(&self, ssl: &mut TlsRef) -> anyhow::Result<()> {
+        let mut store = X509StoreBuilder::new().context("failed to create x509 store")?;
+        let mut names = Stack::new().context("failed to create ca list")?;
+
+        for ca in self.cas.iter() {
+            store
+                .add_cert(ca.clone())
+                .context("failed to add client ca to store")?;
+            names
+                .push(ca.subject_name().to_owned()?)
+                .context("failed to add client ca name")?;
+        }
+
+        ssl.set_verify_cert_store(store.build())
+            .context("failed to set client ca store")?;
+        ssl.set_client_ca_list(names);
+
+        let mode = match self.mode {
+            ClientAuthMode::Require => SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+            ClientAuthMode::Optional => SslVerifyMode::PEER,
+        };
+        ssl.set_verify(mode);
+
+        Ok(())
+    }
+}
+
+impl ClientIdentity {
+    pub fn from_ssl(ssl: &TlsRef) -> Option<Self> {
+        let cert = ssl.peer_certificate()?;
+
+        if ssl.verify_result() != X509VerifyResult::OK {
+            return None;
+        }
+
+        let sans = cert
+            .subject_alt_names()
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|name| {
+                        if let Some(dns) = name.dnsname() {
+                            Some(format!("DNS:{dns}"))
+                        } else if let Some(email) = name.email() {
+                            Some(format!("email:{email}"))
+                        } else if let Some(uri) = name.uri() {
+                            Some(format!("URI:{uri}"))
+                        } else {
+                            name.ipaddress()
+                                .and_then(format_ip)
+                                .map(|ip| format!("IP:{ip}"))
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Some(Self {
+            subject: format_name(cert.subject_name()),
+            sans,
+        })
+    }
+}
+
+fn format_name(name: &X509NameRef) -> String {
+    name.entries()
+        .filter_map(|entry| {
+            let key = entry.object().nid().short_name().ok()?;
+            let value = entry.data().as_utf8().ok()?;
+            Some(format!("{key}={value}"))
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn format_ip(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+        16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+        _ => None,
+    }
+}
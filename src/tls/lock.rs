@@ -0,0 +1,269 @@
+use anyhow::Context;
+use openssl::rand::rand_bytes;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncWriteExt;
+use tokio::task::JoinHandle;
+
+use crate::redis::RedisClient;
+
+const LOCK_KEY_PREFIX: &str = "swarmly:lock:";
+const FENCE_KEY_PREFIX: &str = "swarmly:fence:";
+
+/// Distributed cert issuance lock for a single domain.
+///
+/// The lease is extended in the background while the lock is held. With redis,
+/// every acquisition also gets a monotonically increasing fencing token, so a
+/// holder whose lease expired can't overwrite a certificate written by the
+/// next holder. Without redis, the lock is a lockfile on the shared `DATA_DIR`.
+pub struct IssuanceLock {
+    backend: LockBackend,
+    value: Vec<u8>,
+    token: Option<u64>,
+    renewal: JoinHandle<()>,
+}
+
+#[derive(Clone)]
+enum LockBackend {
+    Redis { redis: RedisClient, key: String },
+    File(PathBuf),
+}
+
+impl IssuanceLock {
+    pub const TTL_SECS: u64 = 300;
+
+    /// Id of this process in lock values. Hostnames are shared by replicas
+    /// with a custom hostname and by CLI runs inside the server's container,
+    /// so the id is random per process.
+    pub fn holder_id() -> &'static str {
+        static ID: OnceLock<String> = OnceLock::new();
+
+        ID.get_or_init(|| {
+            let mut id = [0u8; 8];
+            rand_bytes(&mut id).expect("failed to generate lock holder id");
+            let hostname = std::env::var("HOSTNAME").unwrap_or_else(|_| "unknown".to_owned());
+            format!("{hostname}-{:016x}", u64::from_le_bytes(id))
+        })
+    }
+
+    pub async fn acquire(
+        redis: Option<&RedisClient>,
+        lock_dir: &Path,
+        domain: &str,
+    ) -> anyhow::Result<Option<Self>> {
+        let mut nonce = [0u8; 8];
+        rand_bytes(&mut nonce).context("failed to generate lock nonce")?;
+        let value = format!("{}:{}", Self::holder_id(), u64::from_le_bytes(nonce)).into_bytes();
+
+        let (backend, token) = match redis {
+            Some(redis) => match acquire_redis(redis, domain, &value).await? {
+                Some(acquired) => acquired,
+                None => return Ok(None),
+            },
+            None => {
+                let path = lock_dir.join(format!("{domain}.lock"));
+                if !acquire_file(&path, &value).await? {
+                    return Ok(None);
+                }
+                (LockBackend::File(path), None)
+            }
+        };
+
+        let renewal = tokio::spawn(renew(backend.clone(), value.clone()));
+
+        Ok(Some(Self {
+            backend,
+            value,
+            token,
+            renewal,
+        }))
+    }
+
+    /// Fencing token of this acquisition, only issued by redis.
+    pub fn token(&self) -> Option<u64> {
+        self.token
+    }
+
+    pub async fn release(self) {
+        self.renewal.abort();
+
+        match &self.backend {
+            LockBackend::Redis { redis, key } => match redis.del_if_eq(key, &self.value).await {
+                Ok(true) => (),
+                Ok(false) => tracing::warn!("cert lock {key} was lost before release"),
+                Err(err) => tracing::warn!("failed to release cert lock {key}: {err:?}"),
+            },
+            LockBackend::File(path) => match tokio::fs::read(path).await {
+                Ok(current) if current == self.value => {
+                    if let Err(err) = tokio::fs::remove_file(path).await {
+                        tracing::warn!("failed to release cert lock {}: {err}", path.display());
+                    }
+                }
+                _ => tracing::warn!("cert lock {} was lost before release", path.display()),
+            },
+        }
+    }
+}
+
+async fn acquire_redis(
+    redis: &RedisClient,
+    domain: &str,
+    value: &[u8],
+) -> anyhow::Result<Option<(LockBackend, Option<u64>)>> {
+    let key = format!("{LOCK_KEY_PREFIX}{domain}");
+
+    let acquired = redis
+        .set_nx(&key, value.to_vec(), IssuanceLock::TTL_SECS)
+        .await
+        .context("failed to acquire cert issuance lock")?;
+
+    if !acquired {
+        return Ok(None);
+    }
+
+    let token = match redis.incr(&format!("{FENCE_KEY_PREFIX}{domain}")).await {
+        Ok(token) => token,
+        Err(err) => {
+            if let Err(err) = redis.del_if_eq(&key, value).await {
+                tracing::warn!("failed to release cert lock for {domain}: {err:?}");
+            }
+            return Err(err.context("failed to get fencing token"));
+        }
+    };
+
+    Ok(Some((
+        LockBackend::Redis {
+            redis: redis.clone(),
+            key,
+        },
+        Some(token),
+    )))
+}
+
+/// Creates the lockfile exclusively. A lockfile that hasn't been touched for
+/// longer than the TTL belongs to a crashed node and is taken over.
+async fn acquire_file(path: &Path, value: &[u8]) -> anyhow::Result<bool> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .context("failed to create locks directory")?;
+    }
+
+    for _ in 0..2 {
+        let created = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .await;
+
+        match created {
+            Ok(mut file) => {
+                file.write_all(value)
+                    .await
+                    .context("failed to write cert lock")?;
+                return Ok(true);
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                if !remove_stale(path).await? {
+                    return Ok(false);
+                }
+            }
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("failed to create cert lock {}", path.display()));
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+/// Removes `path` if it expired. The file is first renamed to a unique name,
+/// so of several nodes racing for a stale lock only one removes it.
+async fn remove_stale(path: &Path) -> anyhow::Result<bool> {
+    let ttl = Duration::from_secs(IssuanceLock::TTL_SECS);
+
+    let (stale, holder) = match tokio::fs::metadata(path).await {
+        Ok(meta) => {
+            let age = meta
+                .modified()
+                .ok()
+                .and_then(|m| SystemTime::now().duration_since(m).ok())
+                .unwrap_or_default();
+            (age > ttl, tokio::fs::read(path).await.unwrap_or_default())
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(true),
+        Err(err) => return Err(err).context("failed to read cert lock"),
+    };
+
+    if !stale {
+        return Ok(false);
+    }
+
+    let mut nonce = [0u8; 8];
+    rand_bytes(&mut nonce).context("failed to generate lock nonce")?;
+    let moved = path.with_extension(format!("stale.{:x}", u64::from_le_bytes(nonce)));
+
+    if tokio::fs::rename(path, &moved).await.is_err() {
+        return Ok(false);
+    }
+
+    // another node may have replaced the stale lock in between, put it back
+    if tokio::fs::read(&moved).await.unwrap_or_default() != holder {
+        let _ = tokio::fs::hard_link(&moved, path).await;
+        let _ = tokio::fs::remove_file(&moved).await;
+        return Ok(false);
+    }
+
+    tracing::warn!(
+        "taking over expired cert lock {} held by {}",
+        path.display(),
+        String::from_utf8_lossy(&holder)
+    );
+    let _ = tokio::fs::remove_file(&moved).await;
+
+    Ok(true)
+}
+
+async fn renew(backend: LockBackend, value: Vec<u8>) {
+    let interval = Duration::from_secs(IssuanceLock::TTL_SECS / 3);
+    let name = match &backend {
+        LockBackend::Redis { key, .. } => key.clone(),
+        LockBackend::File(path) => path.display().to_string(),
+    };
+
+    loop {
+        tokio::time::sleep(interval).await;
+
+        let result = match &backend {
+            LockBackend::Redis { redis, key } => redis
+                .expire_if_eq(key, &value, IssuanceLock::TTL_SECS)
+                .await
+                .map_err(|err| format!("{err:#}")),
+            LockBackend::File(path) => touch_if_eq(path, &value)
+                .await
+                .map_err(|err| err.to_string()),
+        };
+
+        match result {
+            Ok(true) => tracing::debug!("extended cert lock {name}"),
+            Ok(false) => {
+                tracing::warn!("cert lock {name} is held by someone else, stopping renewal");
+                return;
+            }
+            Err(err) => tracing::warn!("failed to extend cert lock {name}: {err}"),
+        }
+    }
+}
+
+async fn touch_if_eq(path: &Path, value: &[u8]) -> std::io::Result<bool> {
+    if tokio::fs::read(path).await? != value {
+        return Ok(false);
+    }
+
+    let file = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+    file.into_std().await.set_modified(SystemTime::now())?;
+
+    Ok(true)
+}
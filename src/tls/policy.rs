@@ -0,0 +1,82 @@
+use anyhow::Context;
+use pingora::listeners::tls::TlsSettings;
+use pingora::protocols::tls::TlsRef;
+use pingora::tls::ssl::SslVersion;
+
+use crate::config::provider::{TlsProfile, TlsVersion};
+
+pub fn profile_from_env() -> anyhow::Result<Option<TlsProfile>> {
+    let var = |key: &str| std::env::var(key).ok();
+
+    TlsProfile::parse(
+        var("TLS_PROFILE").as_deref(),
+        var("TLS_MIN_VERSION").as_deref(),
+        var("TLS_CIPHERS").as_deref(),
+        var("TLS_CIPHERSUITES").as_deref(),
+    )
+    .context("invalid global tls profile")
+}
+
+pub fn apply_global(settings: &mut TlsSettings, profile: &TlsProfile) -> anyhow::Result<()> {
+    settings
+        .set_min_proto_version(Some(ssl_version(profile.min_version)))
+        .context("failed to set min tls version")?;
+
+    if let Some(ciphers) = &profile.ciphers {
+        settings
+            .set_cipher_list(ciphers)
+            .context("failed to set tls ciphers")?;
+    }
+
+    if let Some(ciphersuites) = &profile.ciphersuites {
+        settings
+            .set_ciphersuites(ciphersuites)
+            .context("failed to set tls 1.3 ciphersuites")?;
+    }
+
+    Ok(())
+}
+
+/// Applies a domain profile during the certificate callback.
+///
+/// The protocol version is already negotiated at this point, so a connection
+/// below the domain's minimum is refused instead of downgraded. TLS 1.2
+/// ciphers are still selected after the callback and can be narrowed here.
+pub fn apply_domain(ssl: &mut TlsRef, profile: &TlsProfile) -> anyhow::Result<()> {
+    let required = ssl_version(profile.min_version);
+
+    let negotiated = ssl
+        .version2()
+        .context("tls version is not negotiated yet")?;
+    if version_rank(negotiated) < version_rank(required) {
+        anyhow::bail!(
+            "client negotiated {}, domain requires {:?} or newer",
+            ssl.version_str(),
+            profile.min_version
+        );
+    }
+
+    if let Some(ciphers) = &profile.ciphers {
+        ssl.set_cipher_list(ciphers)
+            .context("failed to set tls ciphers")?;
+    }
+
+    Ok(())
+}
+
+fn ssl_version(version: TlsVersion) -> SslVersion {
+    match version {
+        TlsVersion::Tls12 => SslVersion::TLS1_2,
+        TlsVersion::Tls13 => SslVersion::TLS1_3,
+    }
+}
+
+fn version_rank(version: SslVersion) -> u8 {
+    if version == SslVersion::TLS1_3 {
+        3
+    } else if version == SslVersion::TLS1_2 {
+        2
+    } else {
+        1
+    }
+}
@@ -1,44 +1,90 @@
 use anyhow::Context;
 use std::collections::HashMap;
 
+use self::envelope::Envelope;
 use super::cert::Certificate;
-use crate::redis::RedisClient;
+use crate::events::{CertEvent, EventBus};
+use crate::metrics;
+use crate::redis::{self, RedisClient};
 
+mod envelope;
+
+const DEFAULT_DATA_DIR: &str = "/opt/swarmly/certs";
+
+#[derive(Clone)]
 enum Backend {
     Filesystem(String),
-    Redis(RedisClient),
+    /// Redis with a local copy of every certificate read or written, served
+    /// while redis is unreachable.
+    Redis {
+        client: RedisClient,
+        cache_dir: String,
+        events: EventBus,
+    },
 }
 
 pub struct TlsStorage {
     cache: HashMap<String, Certificate>,
     backend: Backend,
+    envelope: Option<Envelope>,
 }
 
 impl TlsStorage {
-    const DEFAULT_DATA_DIR: &str = "/opt/swarmly/certs";
     const CERT_KEY_PREFIX: &str = "swarmly:cert:";
     const CERT_TTL_SECS: u64 = 80 * 24 * 3600;
 
-    pub fn from_env(redis: Option<RedisClient>) -> Self {
-        match redis {
-            Some(client) => Self {
-                cache: HashMap::new(),
-                backend: Backend::Redis(client),
+    pub fn from_env(redis: Option<(RedisClient, EventBus)>) -> anyhow::Result<Self> {
+        let dir = data_dir();
+
+        let backend = match redis {
+            Some((client, events)) => Backend::Redis {
+                client,
+                cache_dir: format!("{dir}/cache"),
+                events,
             },
-            None => {
-                let dir =
-                    std::env::var("DATA_DIR").unwrap_or_else(|_| Self::DEFAULT_DATA_DIR.to_owned());
-                let dir = dir.trim().trim_end_matches('/').to_owned();
-                Self {
-                    cache: HashMap::new(),
-                    backend: Backend::Filesystem(dir),
-                }
-            }
+            None => Backend::Filesystem(dir),
+        };
+
+        let envelope = Envelope::from_env().context("failed to load encryption keys")?;
+
+        if let Some(envelope) = &envelope {
+            tracing::info!(
+                "certificate encryption enabled, active key: {}",
+                envelope.active_key_id()
+            );
+        }
+
+        Ok(Self {
+            cache: HashMap::new(),
+            backend,
+            envelope,
+        })
+    }
+
+    /// Another handle on the same backend with its own in-memory copies,
+    /// for reads that shouldn't wait on the owner of this one.
+    pub fn detached(&self) -> Self {
+        Self {
+            cache: HashMap::new(),
+            backend: self.backend.clone(),
+            envelope: self.envelope.clone(),
         }
     }
 
-    pub async fn set(&mut self, domain: &str, cert: Certificate) -> anyhow::Result<()> {
-        let bytes = cert.to_bytes();
+    /// Stores `cert` for `domain`. With a fencing token, the write is rejected
+    /// if a holder of a newer issuance lock already stored a certificate.
+    pub async fn set(
+        &mut self,
+        domain: &str,
+        cert: Certificate,
+        fence: Option<u64>,
+    ) -> anyhow::Result<()> {
+        let bytes = match &self.envelope {
+            Some(envelope) => envelope
+                .seal(domain.as_bytes(), &cert.to_bytes())
+                .context("failed to encrypt certificate")?,
+            None => cert.to_bytes(),
+        };
 
         match &self.backend {
             Backend::Filesystem(dir) => {
@@ -46,19 +92,39 @@ impl TlsStorage {
                 tokio::fs::create_dir_all(dir)
                     .await
                     .context("failed to create certs directory")?;
-                tokio::fs::write(&path, &bytes)
+                write_atomic(&path, &bytes)
                     .await
                     .context("failed to save cert to file")?;
             }
-            Backend::Redis(client) => {
+            Backend::Redis {
+                client,
+                cache_dir,
+                events,
+            } => {
                 let key = format!("{}{}", Self::CERT_KEY_PREFIX, domain);
-                client
-                    .set(&key, bytes, Self::CERT_TTL_SECS)
-                    .await
-                    .context("failed to save cert to redis")?;
+                match fence {
+                    Some(token) => {
+                        let written = client
+                            .set_fenced(&key, &bytes, token, Self::CERT_TTL_SECS)
+                            .await
+                            .context("failed to save cert to redis")?;
+                        if !written {
+                            anyhow::bail!(
+                                "cert for {domain} was rejected, a newer lock holder already stored one (token {token})"
+                            );
+                        }
+                    }
+                    None => client
+                        .set(&key, bytes.clone(), Self::CERT_TTL_SECS)
+                        .await
+                        .context("failed to save cert to redis")?,
+                }
+                write_cache(cache_dir, domain, &bytes).await;
+                events.publish(CertEvent::Updated(domain.to_owned())).await;
             }
         }
 
+        record_expiry(domain, &cert);
         self.cache.insert(domain.to_owned(), cert);
 
         Ok(())
@@ -71,6 +137,108 @@ impl TlsStorage {
         }
     }
 
+    /// Drops the in-memory copies, so the next lookups go to the backend again.
+    pub fn clear_cache(&mut self) {
+        self.cache.clear();
+    }
+
+    /// Applies a change made by another node. A deleted or revoked cert is
+    /// also removed from the local redis cache, so it isn't served during an outage.
+    pub async fn apply_event(&mut self, event: &CertEvent) {
+        let domain = event.domain();
+        self.cache.remove(domain);
+
+        if let (CertEvent::Deleted(_) | CertEvent::Revoked(_), Backend::Redis { cache_dir, .. }) =
+            (event, &self.backend)
+        {
+            let path = cert_path(cache_dir, domain);
+            match tokio::fs::remove_file(&path).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => tracing::warn!("failed to remove cert cache {path}: {e}"),
+            }
+        }
+    }
+
+    /// Domains with a stored certificate.
+    pub async fn list(&self) -> anyhow::Result<Vec<String>> {
+        let mut domains = match &self.backend {
+            Backend::Filesystem(dir) => {
+                let mut entries = match tokio::fs::read_dir(dir).await {
+                    Ok(entries) => entries,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+                    Err(e) => return Err(e).context("failed to read certs directory"),
+                };
+
+                let mut domains = Vec::new();
+                while let Some(entry) = entries
+                    .next_entry()
+                    .await
+                    .context("failed to read certs directory")?
+                {
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    if let Some(domain) = name.strip_suffix(".cert") {
+                        domains.push(domain.to_owned());
+                    }
+                }
+                domains
+            }
+            Backend::Redis { client, .. } => client
+                .keys_with_prefix(Self::CERT_KEY_PREFIX)
+                .await?
+                .into_iter()
+                .filter_map(|key| key.strip_prefix(Self::CERT_KEY_PREFIX).map(str::to_owned))
+                .collect(),
+        };
+
+        domains.sort();
+        Ok(domains)
+    }
+
+    /// Removes the certificate of `domain`, other nodes drop their copies.
+    pub async fn delete(&mut self, domain: &str) -> anyhow::Result<bool> {
+        self.remove(CertEvent::Deleted(domain.to_owned())).await
+    }
+
+    /// Removes the certificate of `domain` after it was revoked at its CA,
+    /// other nodes stop serving it right away.
+    pub async fn revoke(&mut self, domain: &str) -> anyhow::Result<bool> {
+        self.remove(CertEvent::Revoked(domain.to_owned())).await
+    }
+
+    async fn remove(&mut self, event: CertEvent) -> anyhow::Result<bool> {
+        let domain = event.domain();
+        self.cache.remove(domain);
+
+        let removed = match &self.backend {
+            Backend::Filesystem(dir) => {
+                match tokio::fs::remove_file(cert_path(dir, domain)).await {
+                    Ok(()) => true,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+                    Err(e) => return Err(e).context("failed to remove cert file"),
+                }
+            }
+            Backend::Redis {
+                client,
+                cache_dir,
+                events,
+            } => {
+                let key = format!("{}{}", Self::CERT_KEY_PREFIX, domain);
+                let removed = client
+                    .del(&key)
+                    .await
+                    .context("failed to remove cert from redis")?;
+                let _ = tokio::fs::remove_file(cert_path(cache_dir, domain)).await;
+                events.publish(event.clone()).await;
+                removed
+            }
+        };
+
+        metrics::CERT_EXPIRY.remove_label_values(&[domain]).ok();
+
+        Ok(removed)
+    }
+
     pub async fn get(&mut self, domain: &str) -> anyhow::Result<Option<&Certificate>> {
         if !self.cache.contains_key(domain) {
             self.fetch_from_backend(domain).await?;
@@ -91,22 +259,101 @@ impl TlsStorage {
                     Err(e) => anyhow::bail!("failed to read cert file: {e:?}"),
                 }
             }
-            Backend::Redis(client) => {
+            Backend::Redis {
+                client, cache_dir, ..
+            } => {
                 let key = format!("{}{}", Self::CERT_KEY_PREFIX, domain);
-                match client.get(&key).await? {
-                    Some(b) => b,
-                    None => return Ok(None),
+                let fetched = if client.is_healthy() {
+                    client.get(&key).await
+                } else {
+                    Err(anyhow::anyhow!("redis is unreachable"))
+                };
+
+                match fetched {
+                    Ok(Some(b)) => {
+                        let bytes = redis::strip_fence(&b).to_vec();
+                        write_cache(cache_dir, domain, &bytes).await;
+                        bytes
+                    }
+                    Ok(None) => return Ok(None),
+                    Err(err) => match tokio::fs::read(cert_path(cache_dir, domain)).await {
+                        Ok(b) => {
+                            tracing::debug!("serving cached cert for {domain}: {err:#}");
+                            b
+                        }
+                        Err(_) => return Err(err),
+                    },
                 }
             }
         };
 
+        let bytes = match (&self.envelope, envelope::is_sealed(&bytes)) {
+            (Some(envelope), true) => envelope
+                .open(domain.as_bytes(), &bytes)
+                .context("failed to decrypt certificate")?,
+            (None, true) => anyhow::bail!("certificate is encrypted but no keys are configured"),
+            (_, false) => bytes,
+        };
+
         let cert = Certificate::from_bytes(&bytes).context("failed to parse certificate")?;
+        record_expiry(domain, &cert);
         self.cache.insert(domain.to_owned(), cert);
 
         Ok(self.cache.get(domain))
     }
 }
 
+fn record_expiry(domain: &str, cert: &Certificate) {
+    if let Some(expires_at) = cert.expires_at() {
+        metrics::CERT_EXPIRY
+            .with_label_values(&[domain])
+            .set(expires_at);
+    }
+}
+
+/// `DATA_DIR`, shared by certificates, challenges and lockfiles.
+pub fn data_dir() -> String {
+    let dir = std::env::var("DATA_DIR").unwrap_or_else(|_| DEFAULT_DATA_DIR.to_owned());
+    dir.trim().trim_end_matches('/').to_owned()
+}
+
 fn cert_path(dir: &str, domain: &str) -> String {
     format!("{}/{}.cert", dir, domain)
 }
+
+/// Writes through a temporary file, so replicas sharing `DATA_DIR` never read
+/// a half written certificate.
+async fn write_atomic(path: &str, bytes: &[u8]) -> std::io::Result<()> {
+    let mut nonce = [0u8; 8];
+    openssl::rand::rand_bytes(&mut nonce).map_err(std::io::Error::other)?;
+    let tmp = format!("{path}.tmp.{:x}", u64::from_le_bytes(nonce));
+
+    tokio::fs::write(&tmp, bytes).await?;
+    if let Err(err) = tokio::fs::rename(&tmp, path).await {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+async fn write_cache(dir: &str, domain: &str, bytes: &[u8]) {
+    let path = cert_path(dir, domain);
+
+    if tokio::fs::read(&path)
+        .await
+        .is_ok_and(|cached| cached == bytes)
+    {
+        return;
+    }
+
+    let result = async {
+        tokio::fs::create_dir_all(dir).await?;
+        tokio::fs::write(&path, bytes).await
+    }
+    .await;
+
+    if let Err(err) = result {
+        tracing::warn!("failed to write cert cache {path}: {err}");
+    }
+}
@@ -0,0 +1,277 @@
+use anyhow::Context;
+use openssl::rand::rand_bytes;
+use openssl::symm::{Cipher, decrypt_aead, encrypt_aead};
+
+const MAGIC: &[u8] = b"SWENC1";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+#[derive(Clone)]
+struct Key {
+    id: String,
+    material: Vec<u8>,
+}
+
+/// Envelope encryption for stored certificates.
+///
+/// Every blob gets its own random data key, which is wrapped with the newest
+/// key encryption key. Older keys are only used for reads, so keys can be
+/// rotated by prepending a new one.
+#[derive(Clone)]
+pub struct Envelope {
+    keys: Vec<Key>,
+}
+
+impl Envelope {
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let raw = match std::env::var("ENCRYPTION_KEYS") {
+            Ok(keys) => keys,
+            Err(_) => match std::env::var("ENCRYPTION_KEYS_FILE") {
+                Ok(path) => std::fs::read_to_string(path.trim())
+                    .with_context(|| format!("failed to read encryption keys from {path}"))?,
+                Err(_) => return Ok(None),
+            },
+        };
+
+        let keys = raw
+            .split([',', '\n'])
+            .map(str::trim)
+            .filter(|k| !k.is_empty())
+            .map(parse_key)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        if keys.is_empty() {
+            anyhow::bail!("encryption keys are configured but empty");
+        }
+
+        Ok(Some(Self { keys }))
+    }
+
+    pub fn active_key_id(&self) -> &str {
+        &self.keys[0].id
+    }
+
+    pub fn seal(&self, aad: &[u8], plain: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let kek = &self.keys[0];
+
+        let mut dek = [0u8; KEY_LEN];
+        rand_bytes(&mut dek).context("failed to generate data key")?;
+
+        let wrapped = encrypt(&kek.material, kek.id.as_bytes(), &dek)?;
+        let payload = encrypt(&dek, aad, plain)?;
+
+        let mut buf =
+            Vec::with_capacity(MAGIC.len() + 1 + kek.id.len() + wrapped.len() + payload.len());
+        buf.extend_from_slice(MAGIC);
+        buf.push(kek.id.len() as u8);
+        buf.extend_from_slice(kek.id.as_bytes());
+        buf.extend_from_slice(&wrapped);
+        buf.extend_from_slice(&payload);
+
+        Ok(buf)
+    }
+
+    pub fn open(&self, aad: &[u8], buf: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let body = buf.strip_prefix(MAGIC).context("buffer is not encrypted")?;
+
+        let (&id_len, body) = body.split_first().context("missing key id")?;
+        let wrapped_len = NONCE_LEN + KEY_LEN + TAG_LEN;
+
+        if body.len() < id_len as usize + wrapped_len + NONCE_LEN + TAG_LEN {
+            anyhow::bail!("encrypted buffer is too short");
+        }
+
+        let (id, body) = body.split_at(id_len as usize);
+        let (wrapped, payload) = body.split_at(wrapped_len);
+
+        // the matching key goes first, the rest are tried in case a key was renamed
+        let mut candidates: Vec<&Key> =
+            self.keys.iter().filter(|k| k.id.as_bytes() == id).collect();
+        candidates.extend(self.keys.iter().filter(|k| k.id.as_bytes() != id));
+
+        let dek = candidates
+            .into_iter()
+            .find_map(|k| decrypt(&k.material, id, wrapped).ok())
+            .with_context(|| {
+                format!(
+                    "no configured key can decrypt data key (key id: {})",
+                    String::from_utf8_lossy(id)
+                )
+            })?;
+
+        decrypt(&dek, aad, payload).context("failed to decrypt payload")
+    }
+}
+
+pub fn is_sealed(buf: &[u8]) -> bool {
+    buf.starts_with(MAGIC)
+}
+
+fn parse_key(entry: &str) -> anyhow::Result<Key> {
+    let (id, material) = entry
+        .split_once(':')
+        .context("encryption key must be in <id>:<base64> form")?;
+
+    let id = id.trim();
+    if id.is_empty() || id.len() > u8::MAX as usize {
+        anyhow::bail!("invalid encryption key id: {id:?}");
+    }
+
+    let material = openssl::base64::decode_block(material.trim())
+        .with_context(|| format!("failed to decode encryption key {id} as base64"))?;
+
+    if material.len() != KEY_LEN {
+        anyhow::bail!(
+            "encryption key {id} must be {KEY_LEN} bytes, got {}",
+            material.len()
+        );
+    }
+
+    Ok(Key {
+        id: id.to_owned(),
+        material,
+    })
+}
+
+fn encrypt(key: &[u8], aad: &[u8], plain: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand_bytes(&mut nonce).context("failed to generate nonce")?;
+
+    let mut tag = [0u8; TAG_LEN];
+    let ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(&nonce),
+        aad,
+        plain,
+        &mut tag,
+    )
+    .context("aes-gcm encryption failed")?;
+
+    let mut buf = Vec::with_capacity(NONCE_LEN + ciphertext.len() + TAG_LEN);
+    buf.extend_from_slice(&nonce);
+    buf.extend_from_slice(&ciphertext);
+    buf.extend_from_slice(&tag);
+
+    Ok(buf)
+}
+
+fn decrypt(key: &[u8], aad: &[u8], buf: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if buf.len() < NONCE_LEN + TAG_LEN {
+        anyhow::bail!("ciphertext is too short");
+    }
+
+    let (nonce, rest) = buf.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+
+    decrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(nonce),
+        aad,
+        ciphertext,
+        tag,
+    )
+    .context("aes-gcm decryption failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(id: &str, byte: u8) -> String {
+        format!("{id}:{}", openssl::base64::encode_block(&[byte; KEY_LEN]))
+    }
+
+    fn envelope(keys: &[String]) -> Envelope {
+        Envelope {
+            keys: keys.iter().map(|k| parse_key(k).unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let envelope = envelope(&[key("k1", 1)]);
+        let sealed = envelope.seal(b"example.com", b"certificate").unwrap();
+
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.windows(11).any(|w| w == b"certificate"));
+        assert_eq!(
+            envelope.open(b"example.com", &sealed).unwrap(),
+            b"certificate"
+        );
+    }
+
+    #[test]
+    fn opens_with_a_rotated_out_key() {
+        let old = envelope(&[key("k1", 1)]);
+        let sealed = old.seal(b"example.com", b"certificate").unwrap();
+
+        let rotated = envelope(&[key("k2", 2), key("k1", 1)]);
+        assert_eq!(rotated.active_key_id(), "k2");
+        assert_eq!(
+            rotated.open(b"example.com", &sealed).unwrap(),
+            b"certificate"
+        );
+
+        // new blobs are wrapped with the new key only
+        let resealed = rotated.seal(b"example.com", b"certificate").unwrap();
+        assert!(old.open(b"example.com", &resealed).is_err());
+    }
+
+    #[test]
+    fn opens_after_a_key_was_renamed() {
+        let sealed = envelope(&[key("k1", 1)])
+            .seal(b"example.com", b"certificate")
+            .unwrap();
+
+        assert_eq!(
+            envelope(&[key("renamed", 1)])
+                .open(b"example.com", &sealed)
+                .unwrap(),
+            b"certificate"
+        );
+    }
+
+    #[test]
+    fn rejects_another_domain() {
+        let envelope = envelope(&[key("k1", 1)]);
+        let sealed = envelope.seal(b"example.com", b"certificate").unwrap();
+
+        assert!(envelope.open(b"example.org", &sealed).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_and_tampered_blobs() {
+        let envelope = envelope(&[key("k1", 1)]);
+        let sealed = envelope.seal(b"example.com", b"certificate").unwrap();
+
+        for len in [0, MAGIC.len(), MAGIC.len() + 3, sealed.len() - 1] {
+            assert!(
+                envelope.open(b"example.com", &sealed[..len]).is_err(),
+                "{len}"
+            );
+        }
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(envelope.open(b"example.com", &tampered).is_err());
+    }
+
+    #[test]
+    fn plaintext_is_not_sealed() {
+        let envelope = envelope(&[key("k1", 1)]);
+
+        assert!(!is_sealed(b"\x00\x01plain certificate bytes"));
+        assert!(envelope.open(b"example.com", b"plain").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_keys() {
+        assert!(parse_key("no-separator").is_err());
+        assert!(parse_key(&key("", 1)).is_err());
+        assert!(parse_key("k1:not base64!").is_err());
+        assert!(parse_key(&format!("k1:{}", openssl::base64::encode_block(&[1; 16]))).is_err());
+    }
+}